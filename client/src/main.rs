@@ -1,13 +1,17 @@
 use std::{
     io::{self, Write},
+    path::PathBuf,
     process::exit,
 };
 
 use log::{error, info};
-use serde_json::json;
 use tokio::net::UnixStream;
 
-use buhao_lib::{BuhaoCodec, ResponseActionType, BUHAO_SOCK_PATH};
+use buhao_lib::{
+    sock_path, ClientCodec, DiffRequest, Encoding, GetByInodeRequest, GetManyRequest, GetRequest,
+    Hello, PinRequest, ReaddirRequest, RefreshRequest, Request, RequestActionType, RequestId,
+    Response, StatsRequest,
+};
 use futures::prelude::*;
 use tokio_util::codec::Framed;
 
@@ -16,8 +20,55 @@ async fn main() {
     // init logger
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let stream = UnixStream::connect(BUHAO_SOCK_PATH).await.unwrap();
-    let (mut writer, mut reader) = Framed::new(stream, BuhaoCodec).split();
+    let stream = UnixStream::connect(sock_path()).await.unwrap();
+    let mut framed = Framed::new(stream, ClientCodec::new());
+
+    // JSON only, so that the traffic could be read while debugging
+    let hello = Hello::new(
+        &[
+            RequestActionType::Get,
+            RequestActionType::GetMany,
+            RequestActionType::Readdir,
+            RequestActionType::Lookup,
+            RequestActionType::GetByInode,
+            RequestActionType::Paths,
+            RequestActionType::Refresh,
+            RequestActionType::Roots,
+            RequestActionType::Stats,
+            RequestActionType::Pin,
+            RequestActionType::Diff,
+        ],
+        &[Encoding::Json],
+        Vec::new(),
+    );
+    framed
+        .send((0, Request::Hello(hello.clone())))
+        .await
+        .unwrap();
+    match framed.next().await {
+        Some(Ok((0, Ok(Response::Hello(server))))) => match server.accepts(&hello) {
+            Ok(()) => {
+                info!("Connected to server speaking version {}", server.version);
+                framed
+                    .codec_mut()
+                    .finish_hello(server.encoding().unwrap_or_default());
+            }
+            Err(e) => {
+                error!("Incompatible server: {}", e);
+                exit(1);
+            }
+        },
+        Some(Ok((0, Ok(Response::Error(e))))) => {
+            error!("Rejected by server: {}", e);
+            exit(1);
+        }
+        response => {
+            error!("Unexpected response to hello: {:?}", response);
+            exit(1);
+        }
+    }
+    let (mut writer, mut reader) = framed.split();
+    let mut next_id: RequestId = 1;
 
     loop {
         let mut input = String::new();
@@ -26,35 +77,121 @@ async fn main() {
         std::io::stdin().read_line(&mut input).unwrap();
         let input = input.trim();
         let (command, args) = input.split_once(' ').unwrap_or((input, ""));
-        let sent_success = match command {
+        let request = match command {
             "exit" => {
                 exit(0);
             }
-            "get" => {
-                let path = args;
-                let payload = json!({
-                    "path": path,
-                });
-                if let Err(e) = writer.send((1, payload)).await {
-                    error!("Failed to send payload: {}", e);
-                    false
-                } else {
-                    true
+            "get" | "lookup" => {
+                // -L follows a symlink at the end, like stat -L
+                let (path, follow) = match args.strip_prefix("-L ") {
+                    Some(path) => (path.trim(), true),
+                    None => (args, false),
+                };
+                let request = GetRequest {
+                    path: PathBuf::from(path),
+                    follow,
+                };
+                match command {
+                    "get" => Some(Request::Get(request)),
+                    _ => Some(Request::Lookup(request)),
                 }
             }
-            "refresh" => {
-                unimplemented!("refresh")
+            "inode" | "paths" => {
+                // inode <root> <id>, or paths <root> <id> for where it is linked
+                let args: Vec<&str> = args.split_whitespace().collect();
+                match (
+                    args.first(),
+                    args.get(1).and_then(|id| id.parse::<u64>().ok()),
+                ) {
+                    (Some(root), Some(id)) => {
+                        let request = GetByInodeRequest {
+                            root: root.to_string(),
+                            id,
+                            generation: None,
+                        };
+                        match command {
+                            "inode" => Some(Request::GetByInode(request)),
+                            _ => Some(Request::Paths(request)),
+                        }
+                    }
+                    _ => {
+                        error!("Usage: {} <root> <id>", command);
+                        None
+                    }
+                }
+            }
+            "getmany" => {
+                let (paths, follow) = match args.strip_prefix("-L ") {
+                    Some(paths) => (paths, true),
+                    None => (args, false),
+                };
+                Some(Request::GetMany(GetManyRequest {
+                    paths: paths.split_whitespace().map(PathBuf::from).collect(),
+                    follow,
+                }))
+            }
+            "readdir" => {
+                // readdir <path> [after]
+                let (path, after) = args.split_once(' ').unwrap_or((args, ""));
+                Some(Request::Readdir(ReaddirRequest {
+                    path: PathBuf::from(path),
+                    after: after.trim().to_string(),
+                    limit: None,
+                }))
+            }
+            // refresh the whole tree without a path
+            "refresh" => Some(Request::Refresh(RefreshRequest {
+                root: None,
+                path: (!args.is_empty()).then(|| PathBuf::from(args)),
+            })),
+            "roots" => Some(Request::Roots),
+            // every root without a name
+            "stats" => Some(Request::Stats(StatsRequest {
+                root: (!args.is_empty()).then(|| args.to_string()),
+            })),
+            "pin" => Some(Request::Pin(PinRequest {
+                root: (!args.is_empty()).then(|| args.to_string()),
+            })),
+            "diff" => {
+                // diff [-n <limit>] <root> <from> [to] [after], -n for the size of a page
+                let mut args: Vec<&str> = args.split_whitespace().collect();
+                let limit = match args.first() {
+                    Some(&"-n") => args.drain(..2.min(args.len())).nth(1).map(str::parse),
+                    _ => None,
+                };
+                let epoch = |i: usize| args.get(i).and_then(|epoch| epoch.parse::<u64>().ok());
+                match (args.first(), epoch(1), limit.transpose()) {
+                    (Some(root), Some(from), Ok(limit)) => Some(Request::Diff(DiffRequest {
+                        root: root.to_string(),
+                        from,
+                        to: epoch(2),
+                        after: args.get(3).unwrap_or(&"").to_string(),
+                        limit,
+                    })),
+                    _ => {
+                        error!("Usage: diff [-n <limit>] <root> <from> [to] [after]");
+                        None
+                    }
+                }
             }
             "help" => {
-                println!("Available commands: get <path>, refresh, help, exit");
-                false
+                println!(
+                    "Available commands: get [-L] <path>, lookup [-L] <path>, inode <root> <id>, paths <root> <id>, getmany [-L] <path>..., readdir <path> [after], refresh [path], roots, stats [root], pin [root], diff [-n <limit>] <root> <from> [to] [after], help, exit"
+                );
+                None
             }
             _ => {
                 error!("Unknown command: {}", command);
-                false
+                None
             }
         };
-        if sent_success {
+        if let Some(request) = request {
+            let id = next_id;
+            next_id = next_id.wrapping_add(1);
+            if let Err(e) = writer.send((id, request)).await {
+                error!("Failed to send payload: {}", e);
+                continue;
+            }
             let response = match reader.next().await {
                 Some(Err(e)) => {
                     error!("Failed to receive response: {}", e);
@@ -64,18 +201,18 @@ async fn main() {
                     error!("Connection closed");
                     continue;
                 }
-                Some(Ok(response)) => response,
-            };
-            let typ = response.0;
-            let payload = response.1;
-            let typ: ResponseActionType = match typ.try_into() {
-                Err(e) => {
-                    error!("Unknown response type: {}", e);
+                Some(Ok((_, Err(e)))) => {
+                    error!("Invalid response: {}", e);
                     continue;
                 }
-                Ok(typ) => typ,
+                Some(Ok((got, Ok(response)))) => {
+                    if got != id {
+                        error!("Response to request {} instead of {}", got, id);
+                    }
+                    response
+                }
             };
-            info!("Response: {:?}, {:?}", typ, payload);
+            info!("Response {}: {:?}", id, response);
         }
     }
 }
@@ -4,7 +4,11 @@ use log::{info, warn};
 use redhook::hook;
 use std::{ffi::c_char, ptr::null_mut};
 
-use crate::{get_path, manager::{DirState, ShadowFd}, set_errno_code};
+use crate::{
+    get_path,
+    manager::{DirState, ShadowFd},
+    set_errno_code,
+};
 
 // Well, libc::DIR is opaque, so we can't really do anything with it
 // But we could assume it shall always be a valid pointer if not provided by us
@@ -43,12 +47,17 @@ fn readdir_get_dirent(dirp: u64) -> Result<(DirectoryItem, i64)> {
         }
     };
     let idx = state.idx;
-    let dirent_item = match info.info.contents {
-        Contents::Directory(ref v) => v.children.get(idx),
-        _ => {
-            warn!("readdir: not a directory");
-            set_errno_code(libc::ENOTDIR);
-            return Err(anyhow::anyhow!("not a directory"));
+    if !matches!(info.info.contents, Contents::Directory(_)) {
+        warn!("readdir: not a directory");
+        set_errno_code(libc::ENOTDIR);
+        return Err(anyhow::anyhow!("not a directory"));
+    }
+    let dirent_item = match dirent!(dirp, idx) {
+        Ok(dirent_item) => dirent_item,
+        Err(e) => {
+            warn!("readdir: failed to fetch entries ({})", e);
+            set_errno_code(libc::EIO);
+            return Err(e);
         }
     };
     let dirent = match dirent_item {
@@ -59,7 +68,7 @@ fn readdir_get_dirent(dirp: u64) -> Result<(DirectoryItem, i64)> {
             return Err(anyhow::anyhow!("end of directory"));
         }
     };
-    Ok((dirent.clone(), idx as i64))
+    Ok((dirent, idx as i64))
 }
 
 macro_rules! alloc_dirent {
@@ -91,7 +100,7 @@ macro_rules! alloc_dirent {
 hook! {
     unsafe fn opendir(dirptr: *const c_char) -> *mut libc::DIR => my_opendir {
         match opendir_hook(dirptr) {
-            Err(_) => redhook::real!(opendir)(dirptr),
+            Err(e) => negative_or_real!(e, null_mut(), redhook::real!(opendir)(dirptr)),
             Ok(fd) => fd,
         }
     }
@@ -109,7 +118,7 @@ hook! {
 
         info!("readdir item: {}", dirent.name);
         let res = alloc_dirent!(dirent, idx, libc::dirent);
-        set_dirstate!(dirp as u64, DirState { idx: idx as usize + 1 });
+        seek_dir!(dirp as u64, idx as usize + 1);
         res
     }
 }
@@ -126,7 +135,7 @@ hook! {
 
         info!("readdir64 item: {}", dirent.name);
         let res = alloc_dirent!(dirent, idx, libc::dirent64);
-        set_dirstate!(dirp as u64, DirState { idx: idx as usize + 1 });
+        seek_dir!(dirp as u64, idx as usize + 1);
         res
     }
 }
@@ -148,4 +157,4 @@ hook! {
         close!(dirp as u64, true);
         0
     }
-}
\ No newline at end of file
+}
@@ -12,14 +12,20 @@ pub fn construct_absoulte_path(path: &str) -> Result<String, std::io::Error> {
         path.to_owned()
     } else {
         let cwd = std::env::current_dir()?;
-        cwd.join(path)
+        let mut absolute = cwd
+            .join(path)
             .clean()
             .to_str()
             .ok_or(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Path is not valid UTF-8",
             ))?
-            .to_owned()
+            .to_owned();
+        // a trailing slash asks for a directory, so it is kept for the server
+        if path.ends_with('/') && !absolute.ends_with('/') {
+            absolute.push('/');
+        }
+        absolute
     };
     Ok(path)
 }
@@ -36,10 +42,10 @@ pub(crate) fn set_errno_code(code: i32) {
     }
 }
 
-macro_rules! get {
-    ($path: expr) => {{
+macro_rules! lookup {
+    ($path: expr, $follow: expr) => {{
         let path = &$crate::construct_absoulte_path($path)?;
-        $crate::manager::MANAGER.with(|m| m.borrow_mut().get(path))
+        $crate::manager::MANAGER.with(|m| m.borrow_mut().lookup(path, $follow))
     }};
 }
 
@@ -50,15 +56,40 @@ macro_rules! open {
     }};
 }
 
+/// Fail with errno and $failed if the server answered the lookup for good, or make the real call
+macro_rules! negative_or_real {
+    ($error: expr, $failed: expr, $real: expr) => {
+        match $crate::manager::negative_errno(&$error) {
+            Some(errno) => {
+                $crate::set_errno_code(errno);
+                $failed
+            }
+            None => $real,
+        }
+    };
+}
+
 macro_rules! get_dirstate {
     ($fd: expr) => {
         $crate::manager::MANAGER.with(|m| m.borrow().get_dirstate($fd).cloned())
     };
 }
 
-macro_rules! set_dirstate {
-    ($fd: expr, $state: expr) => {
-        $crate::manager::MANAGER.with(|m| m.borrow_mut().set_dirstate($fd, $state))
+macro_rules! seek_dir {
+    ($fd: expr, $idx: expr) => {
+        $crate::manager::MANAGER.with(|m| m.borrow_mut().seek_dir($fd, $idx))
+    };
+}
+
+macro_rules! dirent {
+    ($fd: expr, $idx: expr) => {
+        $crate::manager::MANAGER.with(|m| m.borrow_mut().dirent($fd, $idx))
+    };
+}
+
+macro_rules! stat_fd {
+    ($fd: expr) => {
+        $crate::manager::MANAGER.with(|m| m.borrow_mut().stat_fd($fd))
     };
 }
 
@@ -86,8 +117,8 @@ const LOWER_FD_BOUND: i32 = 0x00800000;
 mod dir;
 mod manager;
 mod open;
-mod stat;
 mod read;
+mod stat;
 
 #[cfg(test)]
 mod tests {
@@ -100,6 +131,7 @@ mod tests {
         std::env::set_current_dir("/tmp/buhao").unwrap();
         let path = "/tmp";
         assert_eq!(construct_absoulte_path(path).unwrap(), path);
+        assert_eq!(construct_absoulte_path("d/").unwrap(), "/tmp/buhao/d/");
         let path = ".";
         assert_eq!(construct_absoulte_path(path).unwrap(), "/tmp/buhao/");
 
@@ -2,17 +2,22 @@ use std::arch::asm;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use buhao_lib::syncframed::SyncFramed;
 use buhao_lib::{
-    BuhaoCodec, Contents, Inode, Item, RequestActionType, ResponseActionType, BUHAO_SOCK_PATH,
+    sock_path, Attributes, BuhaoError, ClientCodec, Contents, DirectoryItem, Encoding, ErrorKind,
+    GetByInodeRequest, GetManyRequest, GetRequest, Hello, Inode, ReaddirRequest, Request,
+    RequestActionType, RequestId, Response, RootInfo,
 };
 use log::warn;
-use serde_json::json;
 
 use crate::{LOWER_DIRFD_BOUND, LOWER_FD_BOUND};
 
+/// Entries of a directory fetched at once, each page is prefetched as a whole
+const READDIR_PAGE_SIZE: usize = 1024;
+
 thread_local! {
     pub static MANAGER: RefCell<Manager> = RefCell::new(Manager::default());
 }
@@ -28,71 +33,231 @@ pub struct ShadowFd {
 #[derive(Debug, Clone)]
 pub struct DirState {
     pub idx: usize,
+    /// Entries from offset on, fetched a page at a time
+    page: Vec<DirectoryItem>,
+    offset: usize,
+    /// Cursor of the page after this one, none after the last page
+    next: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct Manager {
-    framed: SyncFramed<UnixStream, BuhaoCodec, Item>,
+    framed: SyncFramed<UnixStream, ClientCodec, (RequestId, Request)>,
+    next_id: RequestId,
     fd_map: HashMap<u64, ShadowFd>,
     next_fd: i32,
     next_dirfd: u64,
     dir_state: HashMap<u64, DirState>,
+    /// Roots managed by server
+    roots: Vec<RootInfo>,
+    /// Entries in the current pages of open directories by path, not followed, until their first lookup
+    prefetched: HashMap<String, Inode>,
+}
+
+/// A lookup the server answered for good, which fails with errno instead of falling back to the real call
+#[derive(Debug)]
+pub struct Negative(pub BuhaoError);
+
+impl std::fmt::Display for Negative {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Negative {}
+
+/// The root the server routes path to, which is the one with the longest prefix
+fn managing_root<'a>(roots: &'a [RootInfo], path: &Path) -> Option<&'a RootInfo> {
+    roots
+        .iter()
+        .filter(|root| path.starts_with(&root.path))
+        .max_by_key(|root| root.path.components().count())
+}
+
+/// errno to fail with, if error is an authoritative negative lookup
+pub fn negative_errno(error: &anyhow::Error) -> Option<i32> {
+    error
+        .downcast_ref::<Negative>()
+        .map(|negative| negative.0.errno)
 }
 
 impl Default for Manager {
     fn default() -> Self {
-        let stream = UnixStream::connect(BUHAO_SOCK_PATH).unwrap();
-        let codec = BuhaoCodec;
-        Self {
+        let stream = UnixStream::connect(sock_path()).unwrap();
+        let codec = ClientCodec::new();
+        let mut manager = Self {
             framed: SyncFramed::new(stream, codec),
+            next_id: 0,
             fd_map: HashMap::new(),
             next_fd: LOWER_FD_BOUND,
             next_dirfd: LOWER_DIRFD_BOUND,
             dir_state: HashMap::new(),
+            roots: Vec::new(),
+            prefetched: HashMap::new(),
+        };
+        // without an agreement, nothing is managed and every call goes to the real filesystem
+        let hello = Hello::new(
+            &[
+                RequestActionType::Get,
+                RequestActionType::GetMany,
+                RequestActionType::Readdir,
+                RequestActionType::Lookup,
+                RequestActionType::GetByInode,
+            ],
+            &Encoding::ALL,
+            Vec::new(),
+        );
+        match manager.interact(Request::Hello(hello.clone())) {
+            Ok(Response::Hello(server)) => match server.accepts(&hello) {
+                Ok(()) => {
+                    let encoding = server.encoding().unwrap_or_default();
+                    manager.framed.codec_mut().finish_hello(encoding);
+                    manager.roots = server.roots;
+                }
+                Err(e) => warn!("incompatible server: {}", e),
+            },
+            Ok(Response::Error(e)) => warn!("rejected by server: {}", e),
+            Ok(resp) => warn!("unexpected response to hello: {:?}", resp),
+            Err(e) => warn!("invalid response to hello: {}", e),
         }
+        manager
     }
 }
 
 macro_rules! check_managed {
     ($self:ident, $path:ident) => {
         if !$self.is_managed($path) {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "not managed path").into());
+            return Err(BuhaoError::new(ErrorKind::Unmanaged, "not managed path").into());
         }
     };
 }
 
 /// Assuming the path is absolute
 impl Manager {
-    pub fn interact(&mut self, item: Item) -> Item {
-        self.framed.send(item).unwrap();
-        self.framed.recv().unwrap()
+    pub fn interact(&mut self, request: Request) -> Result<Response, BuhaoError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.framed.send((id, request)).unwrap();
+        loop {
+            let (got, response) = self.framed.recv().unwrap();
+            if got == id {
+                return response;
+            }
+            // left over from a request whose response was never read
+            warn!("discarding response to request {}: {:?}", got, response);
+        }
     }
 
     pub fn is_managed(&self, path: &str) -> bool {
-        // TODO: get managed path from server
-        path.starts_with("/tmp/buhao/") || path == "/tmp/buhao"
+        managing_root(&self.roots, Path::new(path)).is_some()
+    }
+
+    /// Whether the root managing path is authoritative, not any other root it is nested in
+    fn is_authoritative(&self, path: &str) -> bool {
+        managing_root(&self.roots, Path::new(path)).is_some_and(|root| root.authoritative)
     }
 
-    /// Get file info from remote server
-    pub fn get(&mut self, path: &str) -> Result<Inode> {
+    /// Get file info from remote server, following a symlink at the end if follow is set.
+    /// Errors from the server are BuhaoError, telling why the lookup failed,
+    /// and wrapped in Negative if the root is authoritative and the path does not resolve.
+    pub fn get(&mut self, path: &str, follow: bool) -> Result<Inode> {
         check_managed!(self, path);
-        let item = (RequestActionType::Get.into(), json!({"path": path}));
-        let resp = self.interact(item);
-        if resp.0 == <ResponseActionType as Into<u8>>::into(ResponseActionType::Ok) {
-            Ok(serde_json::from_value(resp.1)?)
-        } else {
-            Err(anyhow::anyhow!("{}", resp.1))
+        if let Some(inode) = self.take_prefetched(path, follow) {
+            return Ok(inode);
+        }
+        let request = Request::Get(GetRequest {
+            path: PathBuf::from(path),
+            follow,
+        });
+        match self.interact(request)? {
+            Response::Inode(inode) => Ok(inode),
+            Response::Error(error) => Err(self.lookup_error(path, error)),
+            resp => Err(anyhow::anyhow!("unexpected response: {:?}", resp)),
+        }
+    }
+
+    /// Like get, for stat(2) which needs nothing but the attributes
+    pub fn lookup(&mut self, path: &str, follow: bool) -> Result<Attributes> {
+        check_managed!(self, path);
+        if let Some(inode) = self.take_prefetched(path, follow) {
+            return Ok(inode.attributes());
+        }
+        let request = Request::Lookup(GetRequest {
+            path: PathBuf::from(path),
+            follow,
+        });
+        match self.interact(request)? {
+            Response::Attributes(attributes) => Ok(attributes),
+            Response::Error(error) => Err(self.lookup_error(path, error)),
+            resp => Err(anyhow::anyhow!("unexpected response: {:?}", resp)),
+        }
+    }
+
+    /// Attributes of the open fd, fetched again by inode and generation.
+    /// The ones from open are kept once the inode is gone or changed, as an open file outlives its path,
+    /// and its number may have been taken by an unrelated file.
+    pub fn stat_fd(&mut self, fd: u64) -> Option<Attributes> {
+        let shadow = self.fd_map.get(&fd)?;
+        let cached = shadow.info.attributes();
+        let root = managing_root(&self.roots, Path::new(&shadow.path))?
+            .name
+            .clone();
+        let request = Request::GetByInode(GetByInodeRequest {
+            root,
+            id: cached.id,
+            generation: shadow.info.generation(),
+        });
+        match self.interact(request) {
+            Ok(Response::Inode(inode)) => {
+                let attributes = inode.attributes();
+                if let Some(shadow) = self.fd_map.get_mut(&fd) {
+                    shadow.info = inode;
+                }
+                Some(attributes)
+            }
+            Ok(Response::Error(error)) if error.kind == ErrorKind::Stale => Some(cached),
+            Ok(resp) => {
+                warn!("unexpected response to fstat: {:?}", resp);
+                Some(cached)
+            }
+            Err(e) => {
+                warn!("invalid response to fstat: {}", e);
+                Some(cached)
+            }
+        }
+    }
+
+    /// The prefetched inode at path, used once so that it is no older than the opendir that fetched it
+    fn take_prefetched(&mut self, path: &str, follow: bool) -> Option<Inode> {
+        let inode = self.prefetched.remove(path)?;
+        // prefetched inodes are not followed
+        if follow && matches!(inode.contents, Contents::Symlink(_)) {
+            return None;
+        }
+        Some(inode)
+    }
+
+    fn lookup_error(&self, path: &str, error: BuhaoError) -> anyhow::Error {
+        match error.kind {
+            ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::Loop
+                if self.is_authoritative(path) =>
+            {
+                Negative(error).into()
+            }
+            _ => error.into(),
         }
     }
 
     pub fn open(&mut self, path: &str, oflag: i32, dir_op: bool) -> Result<u64> {
-        let inode = self.get(path)?;
+        let inode = self.get(path, oflag & libc::O_NOFOLLOW == 0)?;
         if dir_op {
             if let Contents::Directory(_) = inode.contents {
             } else {
-                return Err(
-                    std::io::Error::new(std::io::ErrorKind::Other, "not a directory").into(),
-                );
+                let error = BuhaoError::new(ErrorKind::NotADirectory, "not a directory");
+                if self.is_authoritative(path) {
+                    return Err(Negative(error).into());
+                }
+                return Err(error.into());
             }
         }
         let shadow_fd = ShadowFd {
@@ -114,15 +279,118 @@ impl Manager {
         }
     }
 
+    /// Look up every entry of the current page of the open directory fd in one round trip,
+    /// for programs like rsync and nginx autoindex that stat each entry right after reading it
+    fn prefetch(&mut self, fd: u64) {
+        let paths = self.page_paths(fd);
+        if paths.is_empty() {
+            return;
+        }
+        let request = Request::GetMany(GetManyRequest {
+            paths: paths.iter().map(PathBuf::from).collect(),
+            follow: false,
+        });
+        match self.interact(request) {
+            Ok(Response::Inodes(inodes)) => {
+                for (path, inode) in paths.into_iter().zip(inodes) {
+                    if let Ok(inode) = inode {
+                        self.prefetched.insert(path, inode);
+                    }
+                }
+            }
+            Ok(resp) => warn!("unexpected response to prefetch: {:?}", resp),
+            Err(e) => warn!("invalid response to prefetch: {}", e),
+        }
+    }
+
+    /// Paths of the entries in the current page of the open directory fd
+    fn page_paths(&self, fd: u64) -> Vec<String> {
+        let (Some(shadow), Some(state)) = (self.fd_map.get(&fd), self.dir_state.get(&fd)) else {
+            return Vec::new();
+        };
+        state
+            .page
+            .iter()
+            .map(|item| {
+                Path::new(&shadow.path)
+                    .join(&item.name)
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    /// Replace the current page of the open directory fd with the next one, and prefetch its entries
+    fn fetch_page(&mut self, fd: u64) -> Result<()> {
+        let (path, after) = match (self.fd_map.get(&fd), self.dir_state.get(&fd)) {
+            (Some(shadow), Some(state)) => (shadow.path.clone(), state.next.clone()),
+            _ => return Err(anyhow::anyhow!("invalid directory fd: {}", fd)),
+        };
+        let Some(after) = after else {
+            return Ok(());
+        };
+        let request = Request::Readdir(ReaddirRequest {
+            path: PathBuf::from(path),
+            after,
+            limit: Some(READDIR_PAGE_SIZE),
+        });
+        let page = match self.interact(request)? {
+            Response::Directory(page) => page,
+            Response::Error(error) => return Err(error.into()),
+            resp => return Err(anyhow::anyhow!("unexpected response: {:?}", resp)),
+        };
+        for path in self.page_paths(fd) {
+            self.prefetched.remove(&path);
+        }
+        if let Some(state) = self.dir_state.get_mut(&fd) {
+            state.offset += state.page.len();
+            state.page = page.entries;
+            state.next = page.next;
+        }
+        self.prefetch(fd);
+        Ok(())
+    }
+
+    /// Entry idx of the open directory fd, none past the end.
+    /// Pages are fetched as idx moves past them.
+    pub fn dirent(&mut self, fd: u64, idx: usize) -> Result<Option<DirectoryItem>> {
+        loop {
+            let state = self
+                .dir_state
+                .get(&fd)
+                .ok_or_else(|| anyhow::anyhow!("invalid directory fd: {}", fd))?;
+            // earlier pages are gone, as there is no seekdir
+            let Some(i) = idx.checked_sub(state.offset) else {
+                return Ok(None);
+            };
+            if let Some(item) = state.page.get(i) {
+                return Ok(Some(item.clone()));
+            }
+            if state.next.is_none() {
+                return Ok(None);
+            }
+            self.fetch_page(fd)?;
+        }
+    }
+
     pub fn close(&mut self, fd: u64, dir_op: bool) {
         if dir_op {
+            for path in self.page_paths(fd) {
+                self.prefetched.remove(&path);
+            }
             self.unregister_dir(fd);
         }
         self.fd_map.remove(&fd);
     }
 
     fn register_dir(&mut self, fd: u64) {
-        self.dir_state.insert(fd, DirState { idx: 0 });
+        let state = DirState {
+            idx: 0,
+            page: Vec::new(),
+            offset: 0,
+            next: Some(String::new()),
+        };
+        self.dir_state.insert(fd, state);
     }
 
     fn unregister_dir(&mut self, fd: u64) {
@@ -133,8 +401,10 @@ impl Manager {
         self.dir_state.get(&fd)
     }
 
-    pub fn set_dirstate(&mut self, fd: u64, state: DirState) {
-        self.dir_state.insert(fd, state);
+    pub fn seek_dir(&mut self, fd: u64, idx: usize) {
+        if let Some(state) = self.dir_state.get_mut(&fd) {
+            state.idx = idx;
+        }
     }
 
     pub fn retrieve_fd(&mut self, fd: u64, open_real: bool) -> Option<ShadowFd> {
@@ -171,3 +441,32 @@ impl Manager {
         shadow
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_managing_root() {
+        let root = |name: &str, path: &str, authoritative| RootInfo {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+            authoritative,
+        };
+        let roots = [
+            root("mirror", "/srv/mirror", true),
+            root("scratch", "/srv/mirror/scratch", false),
+        ];
+        let name =
+            |path: &str| managing_root(&roots, Path::new(path)).map(|root| root.name.as_str());
+        assert_eq!(name("/srv/mirror/debian"), Some("mirror"));
+        // a root nested in an authoritative one decides for itself
+        assert_eq!(name("/srv/mirror/scratch/file"), Some("scratch"));
+        assert!(
+            !managing_root(&roots, Path::new("/srv/mirror/scratch/file"))
+                .unwrap()
+                .authoritative
+        );
+        assert_eq!(name("/srv/mirror-old"), None);
+    }
+}
@@ -1,5 +1,8 @@
 /// Open files and directories.
-use crate::{get_path, manager::ShadowFd};
+use crate::{
+    get_path,
+    manager::{Negative, ShadowFd},
+};
 use anyhow::Result;
 use log::{info, warn};
 use redhook::hook;
@@ -14,7 +17,14 @@ fn open_hook(ptr: *const c_char, oflag: i32, mode: u32) -> Result<i32> {
         }
     };
     info!("open: {}, {}, {}", path, oflag, mode);
-    let fd = open!(path.as_str(), oflag, false)?;
+    let fd = match open!(path.as_str(), oflag, false) {
+        // a missing file could be created, so it is up to the real call
+        Err(e) if oflag & libc::O_CREAT != 0 => match e.downcast::<Negative>() {
+            Ok(Negative(error)) => return Err(error.into()),
+            Err(e) => return Err(e),
+        },
+        result => result?,
+    };
     info!("using fake fd: {}", fd);
     Ok(fd as i32)
 }
@@ -22,7 +32,7 @@ fn open_hook(ptr: *const c_char, oflag: i32, mode: u32) -> Result<i32> {
 hook! {
     unsafe fn open(ptr: *const c_char, oflag: i32, mode: u32) -> i32 => my_open {
         match open_hook(ptr, oflag, mode) {
-            Err(_) => redhook::real!(open)(ptr, oflag, mode),
+            Err(e) => negative_or_real!(e, -1, redhook::real!(open)(ptr, oflag, mode)),
             Ok(fd) => fd,
         }
     }
@@ -31,8 +41,12 @@ hook! {
 hook! {
     // TODO: when dirfd != AT_FDCWD
     unsafe fn openat(dirfd: i32, ptr: *const c_char, flags: i32, mode: u32) -> i32 => my_openat {
+        if dirfd != libc::AT_FDCWD {
+            warn!("openat: dirfd != AT_FDCWD (fallback)");
+            return redhook::real!(openat)(dirfd, ptr, flags, mode);
+        }
         match open_hook(ptr, flags, mode) {
-            Err(_) => redhook::real!(openat)(dirfd, ptr, flags, mode),
+            Err(e) => negative_or_real!(e, -1, redhook::real!(openat)(dirfd, ptr, flags, mode)),
             Ok(fd) => fd,
         }
     }
@@ -41,7 +55,7 @@ hook! {
 hook! {
     unsafe fn open64(ptr: *const c_char, oflag: i32, mode: u32) -> i32 => my_open64 {
         match open_hook(ptr, oflag, mode) {
-            Err(_) => redhook::real!(open64)(ptr, oflag, mode),
+            Err(e) => negative_or_real!(e, -1, redhook::real!(open64)(ptr, oflag, mode)),
             Ok(fd) => fd,
         }
     }
@@ -50,8 +64,12 @@ hook! {
 hook! {
     // TODO: when dirfd != AT_FDCWD
     unsafe fn openat64(dirfd: i32, ptr: *const c_char, flags: i32, mode: u32) -> i32 => my_openat64 {
+        if dirfd != libc::AT_FDCWD {
+            warn!("openat64: dirfd != AT_FDCWD (fallback)");
+            return redhook::real!(openat64)(dirfd, ptr, flags, mode);
+        }
         match open_hook(ptr, flags, mode) {
-            Err(_) => redhook::real!(openat64)(dirfd, ptr, flags, mode),
+            Err(e) => negative_or_real!(e, -1, redhook::real!(openat64)(dirfd, ptr, flags, mode)),
             Ok(fd) => fd,
         }
     }
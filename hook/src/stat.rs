@@ -1,27 +1,25 @@
 use anyhow::Result;
-use buhao_lib::{Contents, Inode, RECURSIVE_LIMIT};
+use buhao_lib::{Attributes, InodeType};
 use libc::AT_FDCWD;
-use log::{debug, info, warn};
+use log::{info, warn};
 use redhook::hook;
 use std::ffi::c_char;
 
-use crate::{get_path, manager::ShadowFd};
-
-
+use crate::get_path;
 
 macro_rules! inode_to_stat {
     ($inode: ident, $buf: ident) => {
         unsafe {
             (*$buf).st_dev = 0;
             (*$buf).st_ino = $inode.id;
-            match $inode.contents {
-                Contents::File => {
+            match $inode.itype {
+                InodeType::File => {
                     (*$buf).st_mode = libc::S_IFREG;
                 }
-                Contents::Directory(_) => {
+                InodeType::Directory => {
                     (*$buf).st_mode = libc::S_IFDIR;
                 }
-                Contents::Symlink(_) => {
+                InodeType::Symlink => {
                     (*$buf).st_mode = libc::S_IFLNK;
                 }
             }
@@ -40,16 +38,7 @@ macro_rules! inode_to_stat {
     };
 }
 
-fn stat_hook(
-    ptr: *const c_char,
-    buf: *mut libc::stat,
-    use_lstat: bool,
-    recursive: usize,
-) -> Result<i32> {
-    if recursive > RECURSIVE_LIMIT {
-        warn!("stat_hook: recursive limit reached");
-        return Err(anyhow::anyhow!("recursive limit reached"));
-    }
+fn stat_hook(ptr: *const c_char, buf: *mut libc::stat, use_lstat: bool) -> Result<i32> {
     let path = match get_path(ptr) {
         Ok(s) => s,
         Err(e) => {
@@ -58,38 +47,15 @@ fn stat_hook(
         }
     };
     info!("stat: {} (lstat: {})", path, use_lstat);
-    let resp: Inode = get!(path.as_str())?;
+    // symlinks are followed by the server, relative to the link and within the root
+    let resp: Attributes = lookup!(path.as_str(), !use_lstat)?;
     info!("{:?}", resp);
-    let is_symlink = matches!(resp.contents, Contents::Symlink(_));
-    // Returns original inode if not symlink or not using lstat
-    if use_lstat || !is_symlink {
-        inode_to_stat!(resp, buf);
-        Ok(0)
-    } else {
-        // is symlink
-        let new_path = match resp.contents {
-            Contents::Symlink(s) => s,
-            _ => {
-                unreachable!("stat_hook: invalid symlink logic");
-            }
-        };
-        debug!("Get a symlink: {}", new_path);
-        let ptr = new_path.as_ptr() as *const c_char;
-        stat_hook(ptr, buf, use_lstat, recursive + 1)
-    }
+    inode_to_stat!(resp, buf);
+    Ok(0)
 }
 
 // TODO
-fn stat64_hook(
-    ptr: *const c_char,
-    buf: *mut libc::stat64,
-    use_lstat: bool,
-    recursive: usize,
-) -> Result<i32> {
-    if recursive > RECURSIVE_LIMIT {
-        warn!("stat_hook: recursive limit reached");
-        return Err(anyhow::anyhow!("recursive limit reached"));
-    }
+fn stat64_hook(ptr: *const c_char, buf: *mut libc::stat64, use_lstat: bool) -> Result<i32> {
     let path = match get_path(ptr) {
         Ok(s) => s,
         Err(e) => {
@@ -98,29 +64,16 @@ fn stat64_hook(
         }
     };
     info!("stat64: {} (lstat64: {})", path, use_lstat);
-    let resp: Inode = get!(path.as_str())?;
+    let resp: Attributes = lookup!(path.as_str(), !use_lstat)?;
     info!("{:?}", resp);
-    let is_symlink = matches!(resp.contents, Contents::Symlink(_));
-    if use_lstat || !is_symlink {
-        inode_to_stat!(resp, buf);
-        Ok(0)
-    } else {
-        // is symlink
-        let new_path = match resp.contents {
-            Contents::Symlink(s) => s,
-            _ => {
-                unreachable!("stat_hook: invalid symlink logic");
-            }
-        };
-        let ptr = new_path.as_ptr() as *const c_char;
-        stat64_hook(ptr, buf, use_lstat, recursive + 1)
-    }
+    inode_to_stat!(resp, buf);
+    Ok(0)
 }
 
 hook! {
     unsafe fn stat(path: *const c_char, buf: *mut libc::stat) -> i32 => my_stat {
-        match stat_hook(path, buf, false, 0) {
-            Err(_) => redhook::real!(stat)(path, buf),
+        match stat_hook(path, buf, false) {
+            Err(e) => negative_or_real!(e, -1, redhook::real!(stat)(path, buf)),
             Ok(fd) => fd,
         }
     }
@@ -128,8 +81,8 @@ hook! {
 
 hook! {
     unsafe fn stat64(path: *const c_char, buf: *mut libc::stat64) -> i32 => my_stat64 {
-        match stat64_hook(path, buf, false, 0) {
-            Err(_) => redhook::real!(stat64)(path, buf),
+        match stat64_hook(path, buf, false) {
+            Err(e) => negative_or_real!(e, -1, redhook::real!(stat64)(path, buf)),
             Ok(fd) => fd,
         }
     }
@@ -141,14 +94,13 @@ hook! {
             return redhook::real!(fstat)(fd, buf);
         }
         info!("fstat: {}", fd);
-        let info: ShadowFd = match retrieve_fd!(fd as u64) {
-            Some(info) => info,
+        let inode: Attributes = match stat_fd!(fd as u64) {
+            Some(inode) => inode,
             None => {
                 warn!("fstat: invalid fd");
                 return -1;
             }
         };
-        let inode = info.info;
         inode_to_stat!(inode, buf);
         0
     }
@@ -160,14 +112,13 @@ hook! {
             return redhook::real!(fstat64)(fd, buf);
         }
         info!("fstat: {}", fd);
-        let info: ShadowFd = match retrieve_fd!(fd as u64) {
-            Some(info) => info,
+        let inode: Attributes = match stat_fd!(fd as u64) {
+            Some(inode) => inode,
             None => {
                 warn!("fstat: invalid fd");
                 return -1;
             }
         };
-        let inode = info.info;
         inode_to_stat!(inode, buf);
         0
     }
@@ -180,8 +131,8 @@ hook! {
             warn!("fstatat: dirfd != AT_FDCWD (fallback)");
             return redhook::real!(fstatat)(dirfd, path, buf, flags);
         }
-        match stat_hook(path, buf, false, 0) {
-            Err(_) => redhook::real!(fstatat)(dirfd, path, buf, flags),
+        match stat_hook(path, buf, flags & libc::AT_SYMLINK_NOFOLLOW != 0) {
+            Err(e) => negative_or_real!(e, -1, redhook::real!(fstatat)(dirfd, path, buf, flags)),
             Ok(fd) => fd,
         }
     }
@@ -189,8 +140,8 @@ hook! {
 
 hook! {
     unsafe fn lstat(path: *const c_char, buf: *mut libc::stat) -> i32 => my_lstat {
-        match stat_hook(path, buf, true, 0) {
-            Err(_) => redhook::real!(lstat)(path, buf),
+        match stat_hook(path, buf, true) {
+            Err(e) => negative_or_real!(e, -1, redhook::real!(lstat)(path, buf)),
             Ok(fd) => fd,
         }
     }
@@ -198,8 +149,8 @@ hook! {
 
 hook! {
     unsafe fn lstat64(path: *const c_char, buf: *mut libc::stat64) -> i32 => my_lstat64 {
-        match stat64_hook(path, buf, true, 0) {
-            Err(_) => redhook::real!(lstat64)(path, buf),
+        match stat64_hook(path, buf, true) {
+            Err(e) => negative_or_real!(e, -1, redhook::real!(lstat64)(path, buf)),
             Ok(fd) => fd,
         }
     }
@@ -0,0 +1,104 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// What went wrong with a request, so that clients could tell a missing path from an unmanaged one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The path does not exist under a managed root
+    NotFound,
+    /// A component in the middle of the path is not a directory
+    NotADirectory,
+    /// Too many levels of symbolic links
+    Loop,
+    /// The inode asked for by id is gone from the epoch, like a stale NFS file handle
+    Stale,
+    /// The path or a symlink in it leads out of the managed root, where only the real filesystem knows
+    OutsideRoot,
+    /// No managed root covers the path, or the filter of its root leaves it out
+    Unmanaged,
+    /// The request is malformed, or names something that does not exist, like a root
+    InvalidRequest,
+    /// The peer speaks another protocol version, or lacks a request type or encoding in need
+    Incompatible,
+    /// Anything else, like a failed database query
+    Internal,
+}
+
+impl ErrorKind {
+    /// The errno a syscall would fail with, or 0 if there is none and the real syscall should decide
+    pub fn errno(self) -> i32 {
+        match self {
+            ErrorKind::NotFound => libc::ENOENT,
+            ErrorKind::NotADirectory => libc::ENOTDIR,
+            ErrorKind::Loop => libc::ELOOP,
+            ErrorKind::Stale => libc::ESTALE,
+            ErrorKind::OutsideRoot | ErrorKind::Unmanaged => 0,
+            ErrorKind::InvalidRequest => libc::EINVAL,
+            ErrorKind::Incompatible => libc::EPROTO,
+            ErrorKind::Internal => libc::EIO,
+        }
+    }
+
+    /// The kind of error an errno from the server's own syscalls stands for
+    pub fn from_errno(errno: i32) -> Self {
+        match errno {
+            libc::ENOENT => ErrorKind::NotFound,
+            libc::ENOTDIR => ErrorKind::NotADirectory,
+            libc::ELOOP => ErrorKind::Loop,
+            libc::ESTALE => ErrorKind::Stale,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Payload of ResponseActionType::Error
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuhaoError {
+    pub kind: ErrorKind,
+    pub errno: i32,
+    pub message: String,
+}
+
+impl BuhaoError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            errno: kind.errno(),
+            message: message.into(),
+        }
+    }
+
+    /// Pick out the error from what went wrong on the server, anywhere in its chain of causes.
+    /// I/O errors keep their errno, and anything else unexpected is internal.
+    pub fn from_anyhow(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<BuhaoError>() {
+                return Self {
+                    message: error.to_string(),
+                    ..e.clone()
+                };
+            }
+            if let Some(errno) = cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|e| e.raw_os_error())
+            {
+                return Self {
+                    kind: ErrorKind::from_errno(errno),
+                    errno,
+                    message: error.to_string(),
+                };
+            }
+        }
+        Self::new(ErrorKind::Internal, error.to_string())
+    }
+}
+
+impl fmt::Display for BuhaoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for BuhaoError {}
@@ -1,22 +1,44 @@
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{io, os::unix::prelude::MetadataExt};
 
 use serde_json::{json, Value};
 use tokio_util::{
-    bytes::{Buf, BufMut},
+    bytes::{Buf, BufMut, Bytes},
     codec::{Decoder, Encoder},
 };
 
+mod error;
+pub use error::{BuhaoError, ErrorKind};
+
+mod protocol;
+pub use protocol::{
+    Change, ChangedPath, ClientCodec, DiffPage, DiffRequest, DirectoryPage, EpochStats,
+    GetByInodeRequest, GetManyRequest, GetRequest, Hello, Message, MessageCodec, PinRequest,
+    ReaddirRequest, RefreshRequest, Request, Response, RootInfo, RootStats, ServerCodec,
+    StatsRequest, StorageStats,
+};
+
 pub mod syncframed;
 
 pub const BUHAO_SOCK_PATH: &str = "/tmp/buhao.sock";
 
+/// Socket path for clients, which could be overridden with the BUHAO_SOCK_PATH environment variable
+pub fn sock_path() -> String {
+    std::env::var("BUHAO_SOCK_PATH").unwrap_or_else(|_| BUHAO_SOCK_PATH.to_string())
+}
+
 pub type InodeId = u64;
 pub const INVALID_PARENT: InodeId = u64::MAX;
 
 pub const RECURSIVE_LIMIT: usize = 10;
 
+/// Bump this when requests or responses change incompatibly. Peers must speak the same version.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Chosen by the client for each request, and sent back with its response
+pub type RequestId = u32;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum InodeType {
     File,
@@ -34,6 +56,7 @@ pub struct DirectoryItem {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DirectoryContents {
     pub parent: InodeId,
+    /// Empty in answers to clients, which list entries with Readdir
     pub children: Vec<DirectoryItem>,
 }
 
@@ -54,10 +77,28 @@ pub struct Inode {
     pub atime: i64,
     pub mtime: i64,
     pub ctime: i64,
+    /// Birth time, seconds and nanoseconds, where the filesystem keeps one
+    #[serde(default)]
+    pub btime: Option<(i64, i64)>,
     pub size: i64,
     pub contents: Contents,
 }
 
+/// What stat(2) tells about an inode, without its contents
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Attributes {
+    pub id: InodeId,
+    pub itype: InodeType,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub size: i64,
+}
+
 impl Inode {
     pub fn new(metadata: std::fs::Metadata, contents: Contents) -> Self {
         Self {
@@ -69,20 +110,101 @@ impl Inode {
             atime: metadata.atime(),
             mtime: metadata.mtime(),
             ctime: metadata.ctime(),
+            btime: metadata
+                .created()
+                .ok()
+                .and_then(|created| created.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|since| (since.as_secs() as i64, since.subsec_nanos() as i64)),
             size: metadata.size() as i64,
             contents,
         }
     }
 
+    pub fn itype(&self) -> InodeType {
+        match self.contents {
+            Contents::File => InodeType::File,
+            Contents::Directory(_) => InodeType::Directory,
+            Contents::Symlink(_) => InodeType::Symlink,
+        }
+    }
+
+    /// What tells the inode apart from a later one taking the same number once it is gone.
+    /// The birth time is set once, unlike ctime which writes, chmod and link change as well;
+    /// without one there is nothing to tell them apart.
+    pub fn generation(&self) -> Option<(i64, i64)> {
+        self.btime
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        Attributes {
+            id: self.id,
+            itype: self.itype(),
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            nlink: self.nlink,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+            size: self.size,
+        }
+    }
+
     pub fn serialize_metadata(&self) -> Result<Value> {
         Ok(json!(&self))
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RequestActionType {
     Refresh,
     Get,
+    Roots,
+    Stats,
+    Pin,
+    Diff,
+    Hello,
+    GetMany,
+    Readdir,
+    Lookup,
+    GetByInode,
+    Paths,
+}
+
+impl RequestActionType {
+    /// Every request type served by this version
+    pub const ALL: [RequestActionType; 12] = [
+        RequestActionType::Refresh,
+        RequestActionType::Get,
+        RequestActionType::Roots,
+        RequestActionType::Stats,
+        RequestActionType::Pin,
+        RequestActionType::Diff,
+        RequestActionType::Hello,
+        RequestActionType::GetMany,
+        RequestActionType::Readdir,
+        RequestActionType::Lookup,
+        RequestActionType::GetByInode,
+        RequestActionType::Paths,
+    ];
+
+    /// Name in Hello, which is stable across versions unlike the type byte
+    pub fn name(self) -> &'static str {
+        match self {
+            RequestActionType::Refresh => "refresh",
+            RequestActionType::Get => "get",
+            RequestActionType::Roots => "roots",
+            RequestActionType::Stats => "stats",
+            RequestActionType::Pin => "pin",
+            RequestActionType::Diff => "diff",
+            RequestActionType::Hello => "hello",
+            RequestActionType::GetMany => "get_many",
+            RequestActionType::Readdir => "readdir",
+            RequestActionType::Lookup => "lookup",
+            RequestActionType::GetByInode => "get_by_inode",
+            RequestActionType::Paths => "paths",
+        }
+    }
 }
 
 impl TryFrom<u8> for RequestActionType {
@@ -92,6 +214,16 @@ impl TryFrom<u8> for RequestActionType {
         match value {
             0 => Ok(RequestActionType::Refresh),
             1 => Ok(RequestActionType::Get),
+            2 => Ok(RequestActionType::Roots),
+            3 => Ok(RequestActionType::Stats),
+            4 => Ok(RequestActionType::Pin),
+            5 => Ok(RequestActionType::Diff),
+            6 => Ok(RequestActionType::Hello),
+            7 => Ok(RequestActionType::GetMany),
+            8 => Ok(RequestActionType::Readdir),
+            9 => Ok(RequestActionType::Lookup),
+            10 => Ok(RequestActionType::GetByInode),
+            11 => Ok(RequestActionType::Paths),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Invalid message type",
@@ -105,6 +237,16 @@ impl From<RequestActionType> for u8 {
         match value {
             RequestActionType::Refresh => 0,
             RequestActionType::Get => 1,
+            RequestActionType::Roots => 2,
+            RequestActionType::Stats => 3,
+            RequestActionType::Pin => 4,
+            RequestActionType::Diff => 5,
+            RequestActionType::Hello => 6,
+            RequestActionType::GetMany => 7,
+            RequestActionType::Readdir => 8,
+            RequestActionType::Lookup => 9,
+            RequestActionType::GetByInode => 10,
+            RequestActionType::Paths => 11,
         }
     }
 }
@@ -115,15 +257,20 @@ impl PartialEq<RequestActionType> for u8 {
     }
 }
 
-pub fn convert_request_tuple(t: (RequestActionType, Value)) -> Item {
-    let (action_type, payload) = t;
-    (action_type.into(), payload)
-}
-
+/// Type of a response, which is the variant of Response it carries
 #[derive(Debug, Clone, Copy)]
 pub enum ResponseActionType {
-    Ok,
+    Inode,
     Error,
+    Epochs,
+    Roots,
+    Stats,
+    Diff,
+    Hello,
+    Inodes,
+    Directory,
+    Attributes,
+    Paths,
 }
 
 impl TryFrom<u8> for ResponseActionType {
@@ -131,8 +278,17 @@ impl TryFrom<u8> for ResponseActionType {
 
     fn try_from(value: u8) -> Result<Self, io::Error> {
         match value {
-            0 => Ok(ResponseActionType::Ok),
+            0 => Ok(ResponseActionType::Inode),
             1 => Ok(ResponseActionType::Error),
+            2 => Ok(ResponseActionType::Epochs),
+            3 => Ok(ResponseActionType::Roots),
+            4 => Ok(ResponseActionType::Stats),
+            5 => Ok(ResponseActionType::Diff),
+            6 => Ok(ResponseActionType::Hello),
+            7 => Ok(ResponseActionType::Inodes),
+            8 => Ok(ResponseActionType::Directory),
+            9 => Ok(ResponseActionType::Attributes),
+            10 => Ok(ResponseActionType::Paths),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Invalid message type",
@@ -144,8 +300,17 @@ impl TryFrom<u8> for ResponseActionType {
 impl From<ResponseActionType> for u8 {
     fn from(value: ResponseActionType) -> Self {
         match value {
-            ResponseActionType::Ok => 0,
+            ResponseActionType::Inode => 0,
             ResponseActionType::Error => 1,
+            ResponseActionType::Epochs => 2,
+            ResponseActionType::Roots => 3,
+            ResponseActionType::Stats => 4,
+            ResponseActionType::Diff => 5,
+            ResponseActionType::Hello => 6,
+            ResponseActionType::Inodes => 7,
+            ResponseActionType::Directory => 8,
+            ResponseActionType::Attributes => 9,
+            ResponseActionType::Paths => 10,
         }
     }
 }
@@ -156,16 +321,102 @@ impl PartialEq<ResponseActionType> for u8 {
     }
 }
 
-pub fn convert_response_tuple(t: (ResponseActionType, Value)) -> Item {
-    let (action_type, payload) = t;
-    (action_type.into(), payload)
+/// How payloads are written, agreed on per connection in Hello
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Readable, for debugging with buhao_client. Hello is always in JSON.
+    #[default]
+    Json,
+    /// MessagePack, which is smaller and faster to parse
+    MessagePack,
+}
+
+impl Encoding {
+    /// Every encoding this version speaks, the preferred one first
+    pub const ALL: [Encoding; 2] = [Encoding::MessagePack, Encoding::Json];
+
+    /// Name in Hello
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|encoding| encoding.name() == name)
+    }
 }
 
 /// Packet design for requests and responses (which works like <https://i3wm.org/docs/ipc.html>):
-/// "buhao"<json payload len (u32)><message type (u8)><json payload>
-#[derive(Debug)]
-pub struct BuhaoCodec;
-pub type Item = (u8, Value);
+/// "buhao"<payload len (u32)><message type (u8)><request id (u32)><payload>
+///
+/// Responses carry the id of their request, so that a connection could have many requests in flight,
+/// answered in any order.
+/// Hello and its answer leave the request id out, as frames did in version 1,
+/// so that peers of any version could read each other's Hello.
+/// Payloads are in the encoding of the connection, which starts as JSON.
+/// Callers use MessageCodec on top of it, which turns items into Request and Response.
+#[derive(Debug, Default)]
+pub struct BuhaoCodec {
+    encoding: Encoding,
+    /// Whether frames carry request ids, which they do once Hello is answered
+    request_ids: bool,
+}
+pub type Item = (RequestId, u8, Bytes);
+
+/// Bytes before the payload, without the request id
+const HEADER_LEN: usize = 5 + 4 + 1;
+const REQUEST_ID_LEN: usize = 4;
+
+impl BuhaoCodec {
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            request_ids: false,
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Switch the encoding of the following payloads, both ways
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Start or stop carrying request ids in the following frames, both ways
+    pub fn set_request_ids(&mut self, request_ids: bool) {
+        self.request_ids = request_ids;
+    }
+
+    fn header_len(&self) -> usize {
+        if self.request_ids {
+            HEADER_LEN + REQUEST_ID_LEN
+        } else {
+            HEADER_LEN
+        }
+    }
+
+    pub fn encode_payload<T: Serialize>(&self, payload: &T) -> io::Result<Vec<u8>> {
+        match self.encoding {
+            Encoding::Json => Ok(serde_json::to_vec(payload)?),
+            // with field names, so that fields could be added with defaults as in JSON
+            Encoding::MessagePack => rmp_serde::to_vec_named(payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    pub fn decode_payload<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, String> {
+        match self.encoding {
+            Encoding::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+        }
+    }
+}
 
 impl Decoder for BuhaoCodec {
     type Item = crate::Item;
@@ -175,7 +426,8 @@ impl Decoder for BuhaoCodec {
         &mut self,
         src: &mut tokio_util::bytes::BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 10 {
+        let header_len = self.header_len();
+        if src.len() < header_len {
             // Data not enough to use
             return Ok(None);
         }
@@ -185,7 +437,7 @@ impl Decoder for BuhaoCodec {
         }
 
         let payload_len = u32::from_be_bytes(src[5..9].try_into().unwrap());
-        if src.len() < 10 + payload_len as usize {
+        if src.len() < header_len + payload_len as usize {
             // Data not enough to use
             return Ok(None);
         }
@@ -193,10 +445,10 @@ impl Decoder for BuhaoCodec {
         src.advance(5 + 4);
 
         let message_type = src.get_u8();
-        let payload = src.split_to(payload_len as usize);
-        let payload = serde_json::from_slice(&payload)?;
+        let id = if self.request_ids { src.get_u32() } else { 0 };
+        let payload = src.split_to(payload_len as usize).freeze();
 
-        Ok(Some((message_type, payload)))
+        Ok(Some((id, message_type, payload)))
     }
 }
 
@@ -208,13 +460,15 @@ impl Encoder<Item> for BuhaoCodec {
         item: Item,
         dst: &mut tokio_util::bytes::BytesMut,
     ) -> Result<(), Self::Error> {
-        let (message_type, payload) = item;
-        let json_bytes = serde_json::to_vec(&payload)?;
-        dst.reserve(10 + json_bytes.len());
+        let (id, message_type, payload) = item;
+        dst.reserve(self.header_len() + payload.len());
         dst.put_slice(b"buhao");
-        dst.put_u32(json_bytes.len() as u32);
+        dst.put_u32(payload.len() as u32);
         dst.put_u8(message_type);
-        dst.extend_from_slice(&json_bytes);
+        if self.request_ids {
+            dst.put_u32(id);
+        }
+        dst.extend_from_slice(&payload);
         Ok(())
     }
 }
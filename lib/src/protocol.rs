@@ -0,0 +1,459 @@
+use std::{collections::BTreeMap, io, marker::PhantomData, path::PathBuf};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio_util::{
+    bytes::Bytes,
+    codec::{Decoder, Encoder},
+};
+
+use crate::{
+    Attributes, BuhaoCodec, BuhaoError, DirectoryItem, Encoding, ErrorKind, Inode, InodeId,
+    RequestActionType, RequestId, ResponseActionType, PROTOCOL_VERSION,
+};
+
+/// Refresh the root named, the root managing path, or every root if neither is given
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub root: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetRequest {
+    pub path: PathBuf,
+    /// Follow a symlink at the end like stat(2), or not like lstat(2)
+    #[serde(default)]
+    pub follow: bool,
+}
+
+/// The inode with id in the root named, failing with ErrorKind::Stale if it is no longer there
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetByInodeRequest {
+    pub root: String,
+    pub id: InodeId,
+    /// Generation of the inode the client knows, which is stale as well if it does not match,
+    /// as the number may have been taken by another inode
+    #[serde(default)]
+    pub generation: Option<(i64, i64)>,
+}
+
+/// Look up every path in one round trip, each one failing on its own.
+/// Answered with a result for each path, in the same order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetManyRequest {
+    pub paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub follow: bool,
+}
+
+/// Entries of the directory at path, following a symlink at the end like opendir(3),
+/// in pages of limit entries ordered by name and starting after the after cursor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReaddirRequest {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub after: String,
+    pub limit: Option<usize>,
+}
+
+/// Epochs and their sizes of the root named, or of every root
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatsRequest {
+    pub root: Option<String>,
+}
+
+/// Pin the root named, or every root, to its current epoch for the rest of the connection
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PinRequest {
+    pub root: Option<String>,
+}
+
+/// Changes of root between epochs from and to (the current one if absent),
+/// in pages of limit paths starting after the after cursor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffRequest {
+    pub root: String,
+    pub from: u64,
+    pub to: Option<u64>,
+    #[serde(default)]
+    pub after: String,
+    pub limit: Option<usize>,
+}
+
+/// First message on a connection, both ways.
+/// It is framed as in version 1, and its type byte and fields are kept across versions,
+/// so that any two peers could tell whether they fit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    /// Names of the request types a client sends, or a server serves
+    pub requests: Vec<String>,
+    /// Payload encodings a client takes in order of preference, or the one a server has chosen
+    pub encodings: Vec<String>,
+    /// Managed roots, only sent by servers
+    #[serde(default)]
+    pub roots: Vec<RootInfo>,
+}
+
+impl Hello {
+    pub fn new(
+        requests: &[RequestActionType],
+        encodings: &[Encoding],
+        roots: Vec<RootInfo>,
+    ) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            requests: requests.iter().map(|r| r.name().to_string()).collect(),
+            encodings: encodings.iter().map(|e| e.name().to_string()).collect(),
+            roots,
+        }
+    }
+
+    /// The encoding chosen by a server, which is known to this version
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encodings
+            .first()
+            .and_then(|encoding| Encoding::from_name(encoding))
+    }
+
+    /// Check that a server saying self could serve a client saying client.
+    /// Both sides check, so that either one rejects an incompatible peer with a clear error.
+    pub fn accepts(&self, client: &Hello) -> Result<(), BuhaoError> {
+        let incompatible = |message| Err(BuhaoError::new(ErrorKind::Incompatible, message));
+        if client.version != self.version {
+            return incompatible(format!(
+                "Protocol version {} of the client does not match version {} of the server",
+                client.version, self.version
+            ));
+        }
+        let missing: Vec<_> = client
+            .requests
+            .iter()
+            .filter(|request| !self.requests.contains(request))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return incompatible(format!("Requests not served: {}", missing.join(", ")));
+        }
+        match self.encoding() {
+            Some(encoding) if client.encodings.iter().any(|e| e == encoding.name()) => Ok(()),
+            _ => incompatible(format!(
+                "No common encoding: the client takes {}, and the server {}",
+                client.encodings.join(", "),
+                self.encodings.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Request {
+    Refresh(RefreshRequest),
+    Get(GetRequest),
+    Roots,
+    Stats(StatsRequest),
+    Pin(PinRequest),
+    Diff(DiffRequest),
+    Hello(Hello),
+    GetMany(GetManyRequest),
+    Readdir(ReaddirRequest),
+    /// Like Get, for the attributes alone
+    Lookup(GetRequest),
+    GetByInode(GetByInodeRequest),
+    /// Every path of the inode, several for hard links
+    Paths(GetByInodeRequest),
+}
+
+#[derive(Debug, Clone)]
+pub enum Response {
+    /// Answer to Get and GetByInode
+    Inode(Inode),
+    Error(BuhaoError),
+    /// Epoch of each root, answer to Refresh and Pin
+    Epochs(BTreeMap<String, u64>),
+    Roots(Vec<RootInfo>),
+    Stats(Vec<RootStats>),
+    Diff(DiffPage),
+    Hello(Hello),
+    /// Answer to GetMany
+    Inodes(Vec<Result<Inode, BuhaoError>>),
+    /// Answer to Readdir
+    Directory(DirectoryPage),
+    /// Answer to Lookup
+    Attributes(Attributes),
+    /// Answer to Paths, relative to the root and sorted
+    Paths(Vec<String>),
+}
+
+/// What clients get to know about a root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootInfo {
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether a missing path under the root could be taken as missing on disk
+    #[serde(default)]
+    pub authoritative: bool,
+}
+
+/// Epochs of a root, for the admin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootStats {
+    pub name: String,
+    pub epoch: u64,
+    pub pinned: Vec<u64>,
+    /// None if the backend keeps nothing but the current epoch
+    pub storage: Option<StorageStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EpochStats {
+    pub epoch: u64,
+    pub inodes: u64,
+    pub dirents: u64,
+    /// Share of the used space in the database, by rows
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub epochs: Vec<EpochStats>,
+    /// Size of the database, which is shared by all roots
+    pub size: u64,
+    /// Free pages not given back yet
+    pub free: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    /// mtime, size, mode or symlink target differs
+    Modified,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangedPath {
+    /// Relative to the root
+    pub path: String,
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffPage {
+    pub changes: Vec<ChangedPath>,
+    /// Give this as `after` for the next page, none if this is the last one
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryPage {
+    pub entries: Vec<DirectoryItem>,
+    /// Give this as `after` for the next page, none if this is the last one
+    pub next: Option<String>,
+}
+
+/// Type byte and payload of a frame
+type Body = (u8, Bytes);
+
+/// A message whose type byte tells how to read its payload, which is in the encoding of codec
+pub trait Message: Sized {
+    fn into_body(self, codec: &BuhaoCodec) -> io::Result<Body>;
+    fn from_body(body: Body, codec: &BuhaoCodec) -> Result<Self, BuhaoError>;
+}
+
+/// Hello is always in JSON, as peers know nothing about each other before it
+fn hello_codec() -> BuhaoCodec {
+    BuhaoCodec::new(Encoding::Json)
+}
+
+fn to_item<T: Serialize>(
+    codec: &BuhaoCodec,
+    message_type: impl Into<u8>,
+    payload: &T,
+) -> io::Result<Body> {
+    Ok((message_type.into(), codec.encode_payload(payload)?.into()))
+}
+
+fn from_payload<T: DeserializeOwned>(
+    codec: &BuhaoCodec,
+    name: &str,
+    payload: &[u8],
+) -> Result<T, BuhaoError> {
+    codec.decode_payload(payload).map_err(|e| {
+        BuhaoError::new(
+            ErrorKind::InvalidRequest,
+            format!("Invalid {} payload: {}", name, e),
+        )
+    })
+}
+
+impl Message for Request {
+    fn into_body(self, codec: &BuhaoCodec) -> io::Result<Body> {
+        match self {
+            Request::Refresh(request) => to_item(codec, RequestActionType::Refresh, &request),
+            Request::Get(request) => to_item(codec, RequestActionType::Get, &request),
+            Request::Roots => to_item(codec, RequestActionType::Roots, &serde_json::json!({})),
+            Request::Stats(request) => to_item(codec, RequestActionType::Stats, &request),
+            Request::Pin(request) => to_item(codec, RequestActionType::Pin, &request),
+            Request::Diff(request) => to_item(codec, RequestActionType::Diff, &request),
+            Request::Hello(hello) => to_item(&hello_codec(), RequestActionType::Hello, &hello),
+            Request::GetMany(request) => to_item(codec, RequestActionType::GetMany, &request),
+            Request::Readdir(request) => to_item(codec, RequestActionType::Readdir, &request),
+            Request::Lookup(request) => to_item(codec, RequestActionType::Lookup, &request),
+            Request::GetByInode(request) => to_item(codec, RequestActionType::GetByInode, &request),
+            Request::Paths(request) => to_item(codec, RequestActionType::Paths, &request),
+        }
+    }
+
+    fn from_body((message_type, payload): Body, codec: &BuhaoCodec) -> Result<Self, BuhaoError> {
+        let action_type = RequestActionType::try_from(message_type)
+            .map_err(|e| BuhaoError::new(ErrorKind::InvalidRequest, e.to_string()))?;
+        Ok(match action_type {
+            RequestActionType::Refresh => {
+                Request::Refresh(from_payload(codec, "Refresh", &payload)?)
+            }
+            RequestActionType::Get => Request::Get(from_payload(codec, "Get", &payload)?),
+            RequestActionType::Roots => Request::Roots,
+            RequestActionType::Stats => Request::Stats(from_payload(codec, "Stats", &payload)?),
+            RequestActionType::Pin => Request::Pin(from_payload(codec, "Pin", &payload)?),
+            RequestActionType::Diff => Request::Diff(from_payload(codec, "Diff", &payload)?),
+            RequestActionType::Hello => {
+                Request::Hello(from_payload(&hello_codec(), "Hello", &payload)?)
+            }
+            RequestActionType::GetMany => {
+                Request::GetMany(from_payload(codec, "GetMany", &payload)?)
+            }
+            RequestActionType::Readdir => {
+                Request::Readdir(from_payload(codec, "Readdir", &payload)?)
+            }
+            RequestActionType::Lookup => Request::Lookup(from_payload(codec, "Lookup", &payload)?),
+            RequestActionType::GetByInode => {
+                Request::GetByInode(from_payload(codec, "GetByInode", &payload)?)
+            }
+            RequestActionType::Paths => Request::Paths(from_payload(codec, "Paths", &payload)?),
+        })
+    }
+}
+
+impl Message for Response {
+    fn into_body(self, codec: &BuhaoCodec) -> io::Result<Body> {
+        match self {
+            Response::Inode(inode) => to_item(codec, ResponseActionType::Inode, &inode),
+            Response::Error(error) => to_item(codec, ResponseActionType::Error, &error),
+            Response::Epochs(epochs) => to_item(codec, ResponseActionType::Epochs, &epochs),
+            Response::Roots(roots) => to_item(codec, ResponseActionType::Roots, &roots),
+            Response::Stats(stats) => to_item(codec, ResponseActionType::Stats, &stats),
+            Response::Diff(page) => to_item(codec, ResponseActionType::Diff, &page),
+            Response::Hello(hello) => to_item(&hello_codec(), ResponseActionType::Hello, &hello),
+            Response::Inodes(inodes) => to_item(codec, ResponseActionType::Inodes, &inodes),
+            Response::Directory(page) => to_item(codec, ResponseActionType::Directory, &page),
+            Response::Attributes(attributes) => {
+                to_item(codec, ResponseActionType::Attributes, &attributes)
+            }
+            Response::Paths(paths) => to_item(codec, ResponseActionType::Paths, &paths),
+        }
+    }
+
+    fn from_body((message_type, payload): Body, codec: &BuhaoCodec) -> Result<Self, BuhaoError> {
+        let action_type = ResponseActionType::try_from(message_type)
+            .map_err(|e| BuhaoError::new(ErrorKind::InvalidRequest, e.to_string()))?;
+        Ok(match action_type {
+            ResponseActionType::Inode => Response::Inode(from_payload(codec, "Inode", &payload)?),
+            ResponseActionType::Error => Response::Error(from_payload(codec, "Error", &payload)?),
+            ResponseActionType::Epochs => {
+                Response::Epochs(from_payload(codec, "Epochs", &payload)?)
+            }
+            ResponseActionType::Roots => Response::Roots(from_payload(codec, "Roots", &payload)?),
+            ResponseActionType::Stats => Response::Stats(from_payload(codec, "Stats", &payload)?),
+            ResponseActionType::Diff => Response::Diff(from_payload(codec, "Diff", &payload)?),
+            ResponseActionType::Hello => {
+                Response::Hello(from_payload(&hello_codec(), "Hello", &payload)?)
+            }
+            ResponseActionType::Inodes => {
+                Response::Inodes(from_payload(codec, "Inodes", &payload)?)
+            }
+            ResponseActionType::Directory => {
+                Response::Directory(from_payload(codec, "Directory", &payload)?)
+            }
+            ResponseActionType::Attributes => {
+                Response::Attributes(from_payload(codec, "Attributes", &payload)?)
+            }
+            ResponseActionType::Paths => Response::Paths(from_payload(codec, "Paths", &payload)?),
+        })
+    }
+}
+
+/// Typed messages over BuhaoCodec with their request ids, reading In and writing Out.
+/// A well-framed message that does not parse is decoded to an error instead of failing the stream,
+/// so that the peer could be told and the connection goes on.
+pub struct MessageCodec<In, Out> {
+    inner: BuhaoCodec,
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+/// Reads requests and writes responses
+pub type ServerCodec = MessageCodec<Request, Response>;
+/// Writes requests and reads responses
+pub type ClientCodec = MessageCodec<Response, Request>;
+
+impl<In, Out> MessageCodec<In, Out> {
+    /// Start in JSON, for Hello
+    pub fn new() -> Self {
+        Self {
+            inner: BuhaoCodec::default(),
+            _messages: PhantomData,
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.inner.encoding()
+    }
+
+    /// Switch to the encoding agreed on in Hello and start carrying request ids, right after it
+    pub fn finish_hello(&mut self, encoding: Encoding) {
+        self.inner.set_encoding(encoding);
+        self.inner.set_request_ids(true);
+    }
+}
+
+impl<In, Out> Default for MessageCodec<In, Out> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<In, Out> std::fmt::Debug for MessageCodec<In, Out> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MessageCodec")
+            .field("encoding", &self.encoding())
+            .finish()
+    }
+}
+
+impl<In: Message, Out> Decoder for MessageCodec<In, Out> {
+    type Item = (RequestId, Result<In, BuhaoError>);
+    type Error = io::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut tokio_util::bytes::BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(self.inner.decode(src)?.map(|(id, message_type, payload)| {
+            (id, In::from_body((message_type, payload), &self.inner))
+        }))
+    }
+}
+
+impl<In, Out: Message> Encoder<(RequestId, Out)> for MessageCodec<In, Out> {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        (id, item): (RequestId, Out),
+        dst: &mut tokio_util::bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        let (message_type, payload) = item.into_body(&self.inner)?;
+        self.inner.encode((id, message_type, payload), dst)
+    }
+}
@@ -4,6 +4,8 @@ use tokio_util::bytes::BytesMut;
 pub struct SyncFramed<T, U, EncoderItem> {
     inner: T,
     codec: U,
+    /// Bytes read past the last decoded item, which begin the next one
+    buf: BytesMut,
     _encoder_item: std::marker::PhantomData<EncoderItem>,
 }
 
@@ -16,10 +18,15 @@ where
         Self {
             inner,
             codec,
+            buf: BytesMut::new(),
             _encoder_item: std::marker::PhantomData,
         }
     }
 
+    pub fn codec_mut(&mut self) -> &mut U {
+        &mut self.codec
+    }
+
     pub fn send(&mut self, item: EncoderItem) -> Result<(), std::io::Error> {
         let mut buf = BytesMut::new();
         self.codec
@@ -30,19 +37,21 @@ where
     }
 
     pub fn recv(&mut self) -> Result<U::Item, std::io::Error> {
-        let mut buf = BytesMut::new();
         loop {
-            let mut inner_buf = [0; 1024];
-            let n = self.inner.read(&mut inner_buf)?;
             // can it be decoded?
-            buf.extend_from_slice(&inner_buf[..n]);
             if let Some(item) = self
                 .codec
-                .decode(&mut buf)
+                .decode(&mut self.buf)
                 .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "failed to decode"))?
             {
                 return Ok(item);
             }
+            let mut inner_buf = [0; 1024];
+            let n = self.inner.read(&mut inner_buf)?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buf.extend_from_slice(&inner_buf[..n]);
         }
     }
 }
@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use buhao_lib::BUHAO_SOCK_PATH;
+use serde::Deserialize;
+
+use crate::filter::Filter;
+
+/// Server configuration, given with `--config <path>`.
+/// Without it, a single root `/tmp/buhao/` is served with the defaults below.
+///
+/// ```toml
+/// database = "/var/lib/buhao/buhao.db"
+/// backend = "SqliteHashMap"
+///
+/// [socket]
+/// path = "/run/buhao.sock"
+/// mode = 0o666
+///
+/// [[root]]
+/// name = "debian"
+/// path = "/srv/mirror/debian"
+/// refresh = { interval = 86400, max_watches = 65536, keep_epochs = 3 }
+/// filter = ["- .~tmp~/", "- .git/"]
+/// scan_threads = 32
+/// authoritative = true
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub socket: SocketConfig,
+    #[serde(default = "default_database")]
+    pub database: PathBuf,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(rename = "root")]
+    pub roots: Vec<RootConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocketConfig {
+    #[serde(default = "default_socket")]
+    pub path: PathBuf,
+    /// Permission bits set on the socket after binding, left to umask if absent
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum Backend {
+    /// Keep everything in memory, and rescan on every start
+    StdHashMap,
+    /// Keep epochs in the database
+    #[default]
+    SqliteHashMap,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RootConfig {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub refresh: RefreshPolicy,
+    /// rsync-style include (`+ pattern`) and exclude (`- pattern`) rules
+    #[serde(default)]
+    pub filter: Vec<String>,
+    /// Threads scanning the tree, one per CPU if absent. Slow storage like NFS benefits from more.
+    pub scan_threads: Option<usize>,
+    /// Let clients answer lookups of paths missing from the cache with ENOENT, without checking the disk.
+    /// Only for roots that nothing but the cache's own refreshes could change behind its back.
+    #[serde(default)]
+    pub authoritative: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshPolicy {
+    /// Seconds between full rescans, none if absent
+    pub interval: Option<u64>,
+    /// Keep the cache current with inotify, watching at most this many directories
+    pub max_watches: Option<usize>,
+    /// Epochs kept in the database after a full rescan, the current one included
+    #[serde(default = "default_keep_epochs")]
+    pub keep_epochs: usize,
+}
+
+impl Default for RefreshPolicy {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            max_watches: None,
+            keep_epochs: default_keep_epochs(),
+        }
+    }
+}
+
+fn default_keep_epochs() -> usize {
+    2
+}
+
+fn default_database() -> PathBuf {
+    PathBuf::from("/tmp/buhao.db")
+}
+
+fn default_socket() -> PathBuf {
+    PathBuf::from(BUHAO_SOCK_PATH)
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            path: default_socket(),
+            mode: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            socket: SocketConfig::default(),
+            database: default_database(),
+            backend: Backend::default(),
+            roots: vec![RootConfig {
+                name: "buhao".to_string(),
+                path: PathBuf::from("/tmp/buhao/"),
+                refresh: RefreshPolicy::default(),
+                filter: Vec::new(),
+                scan_threads: None,
+                authoritative: false,
+            }],
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        if config.roots.is_empty() {
+            anyhow::bail!("No root is configured");
+        }
+        for root in &config.roots {
+            if !root.path.is_absolute() {
+                anyhow::bail!("Path of root {} is not absolute", root.name);
+            }
+            if root.refresh.interval == Some(0) {
+                anyhow::bail!("Refresh interval of root {} is zero", root.name);
+            }
+            if root.refresh.keep_epochs == 0 {
+                anyhow::bail!("Root {} keeps no epochs", root.name);
+            }
+            if root.scan_threads == Some(0) {
+                anyhow::bail!("Scan threads of root {} is zero", root.name);
+            }
+            Filter::parse(&root.filter)
+                .with_context(|| format!("Invalid filter of root {}", root.name))?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            database = "/var/lib/buhao.db"
+            backend = "StdHashMap"
+            socket = { path = "/run/buhao.sock", mode = 0o660 }
+
+            [[root]]
+            name = "debian"
+            path = "/srv/debian"
+            refresh = { interval = 3600, max_watches = 100, keep_epochs = 5 }
+            filter = ["- .~tmp~/"]
+            scan_threads = 16
+            authoritative = true
+
+            [[root]]
+            name = "ubuntu"
+            path = "/srv/ubuntu"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.socket.path, Path::new("/run/buhao.sock"));
+        assert_eq!(config.socket.mode, Some(0o660));
+        assert!(matches!(config.backend, Backend::StdHashMap));
+        assert_eq!(config.roots.len(), 2);
+        assert_eq!(config.roots[0].refresh.interval, Some(3600));
+        assert_eq!(config.roots[1].refresh.max_watches, None);
+        assert_eq!(config.roots[0].refresh.keep_epochs, 5);
+        assert_eq!(config.roots[1].refresh.keep_epochs, 2);
+        assert_eq!(config.roots[0].filter, vec!["- .~tmp~/"]);
+        assert!(config.roots[1].filter.is_empty());
+        assert_eq!(config.roots[0].scan_threads, Some(16));
+        assert!(config.roots[0].authoritative);
+        assert!(!config.roots[1].authoritative);
+
+        // defaults
+        let config = Config::parse("[[root]]\nname = \"a\"\npath = \"/a\"").unwrap();
+        assert_eq!(config.socket.path, Path::new(BUHAO_SOCK_PATH));
+        assert!(matches!(config.backend, Backend::SqliteHashMap));
+
+        assert!(Config::parse("root = []").is_err());
+        assert!(Config::parse("[[root]]\nname = \"a\"\npath = \"a\"").is_err());
+        assert!(Config::parse("[[root]]\nname = \"a\"\npath = \"/a\"\ntypo = 1").is_err());
+        assert!(Config::parse(
+            "[[root]]\nname = \"a\"\npath = \"/a\"\nrefresh = { keep_epochs = 0 }"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::{fmt, path::Path};
+
+use anyhow::{anyhow, Result};
+
+/// rsync-style include/exclude rules, checked in order: the first matching rule decides,
+/// and entries matching no rule are included.
+///
+/// Each rule is `- pattern` (exclude) or `+ pattern` (include), and a bare pattern excludes.
+/// Like rsync:
+/// - a pattern ending with `/` only matches directories
+/// - a pattern starting with `/` is anchored at the root
+/// - a pattern containing `/` or `**` matches the tail of the path, otherwise only the last component
+/// - `*` matches anything but `/`, `**` matches anything, and `?` matches one character but `/`
+///
+/// Nothing below an excluded directory is scanned, so it could not be included back.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    include: bool,
+    pattern: String,
+    anchored: bool,
+    whole_path: bool,
+    dir_only: bool,
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Self> {
+        let (include, pattern) = match rule.split_once(' ') {
+            Some(("+", pattern)) => (true, pattern),
+            Some(("-", pattern)) => (false, pattern),
+            _ => (false, rule),
+        };
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let (pattern, anchored) = match pattern.strip_prefix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        if pattern.is_empty() {
+            return Err(anyhow!("Empty filter pattern: {:?}", rule));
+        }
+        Ok(Self {
+            include,
+            pattern: pattern.to_string(),
+            anchored,
+            whole_path: anchored || pattern.contains('/') || pattern.contains("**"),
+            dir_only,
+        })
+    }
+
+    /// path is relative to the root, with components separated by '/'
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let pattern = self.pattern.as_bytes();
+        if self.anchored {
+            glob_match(pattern, path.as_bytes())
+        } else if self.whole_path {
+            // any tail that starts at a component
+            std::iter::once(0)
+                .chain(path.match_indices('/').map(|(i, _)| i + 1))
+                .any(|start| glob_match(pattern, &path.as_bytes()[start..]))
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            glob_match(pattern, name.as_bytes())
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}{}{}",
+            if self.include { '+' } else { '-' },
+            if self.anchored { "/" } else { "" },
+            self.pattern,
+            if self.dir_only { "/" } else { "" }
+        )
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        [b'*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => glob_match(rest, text),
+            _ => false,
+        },
+        [p, rest @ ..] => match text {
+            [c, text @ ..] if c == p => glob_match(rest, text),
+            _ => false,
+        },
+    }
+}
+
+impl Filter {
+    pub fn parse<S: AsRef<str>>(rules: &[S]) -> Result<Self> {
+        Ok(Self {
+            rules: rules
+                .iter()
+                .map(|rule| Rule::parse(rule.as_ref()))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Whether the entry at path (relative to the root) should be left out of the cache
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        let path = path.to_string_lossy();
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&path, is_dir))
+            .is_some_and(|rule| !rule.include)
+    }
+}
+
+/// The rules one per line, in the form they parse from
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for rule in &self.rules {
+            writeln!(f, "{}", rule)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse(&[
+            "+ /keep/.git/",
+            "- .git/",
+            ".~tmp~/",
+            "- *.lock",
+            "- /staging",
+            "- pool/**/incoming/",
+            "- ?.tmp",
+        ])
+        .unwrap();
+        let excluded = |path: &str, is_dir| filter.is_excluded(Path::new(path), is_dir);
+
+        assert!(excluded(".git", true));
+        assert!(excluded("debian/.git", true));
+        assert!(!excluded("keep/.git", true));
+        // directory-only patterns
+        assert!(!excluded("debian/.git", false));
+        assert!(excluded("debian/.~tmp~", true));
+        assert!(excluded("db.lock", false));
+        assert!(excluded("a/b/db.lock", false));
+        assert!(!excluded("db.lock.d/file", false));
+        // anchored patterns
+        assert!(excluded("staging", true));
+        assert!(!excluded("debian/staging", true));
+        // patterns with a slash match a tail of the path
+        assert!(excluded("debian/pool/main/x/incoming", true));
+        assert!(!excluded("debian/main/incoming", true));
+        assert!(excluded("a.tmp", false));
+        assert!(!excluded("ab.tmp", false));
+
+        assert!(!Filter::default().is_excluded(Path::new("anything"), false));
+        // written back in the form it parses from
+        let written = filter.to_string();
+        let rules: Vec<&str> = written.lines().collect();
+        assert_eq!(rules[2], "- .~tmp~/");
+        assert_eq!(Filter::parse(&rules).unwrap().to_string(), written);
+        assert!(Filter::parse(&["- /"]).is_err());
+    }
+}
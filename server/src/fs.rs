@@ -2,213 +2,618 @@ use anyhow::anyhow;
 use anyhow::Result;
 use buhao_lib::InodeType;
 use buhao_lib::RECURSIVE_LIMIT;
-use log::warn;
+use buhao_lib::{BuhaoError, ErrorKind};
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::path::Component;
 use std::{
-    os::unix::prelude::MetadataExt,
+    os::unix::prelude::{MetadataExt, OsStrExt},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use buhao_lib::{Contents, DirectoryContents, DirectoryItem, Inode, InodeId, INVALID_PARENT};
+use buhao_lib::{Contents, DirectoryItem, DirectoryPage, Inode, InodeId, INVALID_PARENT};
 
 use crate::hashmapshim::HashMapShim;
+use crate::hashmapshim::InodeMap;
+use crate::hashmapshim::Maintenance;
 use crate::hashmapshim::SqliteHashMap;
 use crate::hashmapshim::StdHashMap;
+use crate::hashmapshim::Transaction;
+use crate::scanner::Scanner;
 
 #[derive(Debug)]
 pub struct Filesystem {
     root_path: PathBuf,
     root: InodeId,
     // inodes: HashMap<InodeId, Inode>,
-    inodes: Box<dyn HashMapShim<InodeId, Inode>>,
+    inodes: Box<dyn InodeMap>,
+    scanner: Arc<Scanner>,
 }
 
 impl Filesystem {
-    #[allow(dead_code)]
-    pub fn new_from_fs(root_path: &Path) -> Self {
-        let root_metadata = std::fs::metadata(root_path).unwrap();
-        let root = root_metadata.ino();
+    pub fn new_from_fs(root_path: &Path, scanner: Scanner) -> Result<Self> {
+        let root = std::fs::metadata(root_path)?.ino();
         // let inodes = HashMap::new();
         let inodes = Box::new(StdHashMap::new());
         let mut fs = Self {
             root_path: root_path.to_path_buf(),
             root,
             inodes,
+            scanner: Arc::new(scanner),
         };
-        let root_files = dfs_list(&mut fs, root_path).unwrap();
-        fs.update(Inode::new(
-            root_metadata,
-            Contents::Directory(DirectoryContents {
-                parent: INVALID_PARENT,
-                children: root_files,
-            }),
-        ));
-        fs
+        fs.populate()?;
+        Ok(fs)
     }
 
-    pub fn new_from_sqlite(root_path: &Path, db_path: &Path) -> Self {
-        let root_metadata = std::fs::metadata(root_path).unwrap();
-        let root = root_metadata.ino();
-        let mut inodes = Box::new(SqliteHashMap::new(db_path).unwrap());
+    pub fn new_from_sqlite(
+        name: &str,
+        root_path: &Path,
+        db_path: &Path,
+        scanner: Scanner,
+    ) -> Result<Self> {
+        let root = std::fs::metadata(root_path)?.ino();
+        let mut inodes =
+            Box::new(SqliteHashMap::new(db_path, name)?.with_filter(scanner.filter().to_string()));
         // does it have root inode?
-        let should_init = inodes.get(&root).is_none();
+        let should_init = !inodes.init_by_root(root)?;
         if should_init {
-            inodes.drop_().unwrap();
-            inodes.create().unwrap();
-        } else {
-            inodes.init_by_root(root);
+            inodes.clear()?;
         }
+        // an epoch scanned with other filter rules has entries it should not, or lacks some
+        let rescan = !should_init && !inodes.filter_matches()?;
         let mut fs = Self {
             root_path: root_path.to_path_buf(),
             root,
             inodes,
+            scanner: Arc::new(scanner),
         };
         if should_init {
-            let root_files = dfs_list(&mut fs, root_path).unwrap();
-            fs.update(Inode::new(
-                root_metadata,
-                Contents::Directory(DirectoryContents {
-                    parent: INVALID_PARENT,
-                    children: root_files,
-                }),
-            ));
+            fs.populate()?;
+        } else if rescan {
+            let mut next = fs.next_epoch()?;
+            next.populate()?;
+            fs = next;
         }
-        fs
+        Ok(fs)
     }
 
-    pub fn update(&mut self, inode: Inode) {
-        self.inodes.insert(inode.id, inode);
+    /// Create an empty filesystem on the next epoch of the same storage.
+    /// The current one keeps serving until the new one is populated and swapped in.
+    pub fn next_epoch(&self) -> Result<Self> {
+        let root = std::fs::metadata(&self.root_path)?.ino();
+        Ok(Self {
+            root_path: self.root_path.clone(),
+            root,
+            inodes: self.inodes.next_epoch()?,
+            scanner: self.scanner.clone(),
+        })
     }
 
-    pub fn open(&self, path: &Path) -> Result<Inode> {
-        // invariant: inode is always a directory until the end of the loop
-        let mut inode = self.inodes.get(&self.root).unwrap();
-        let relative = if path.is_absolute() {
-            path.strip_prefix(&self.root_path)
-                .map_err(|x| anyhow!("Unmanaged path: {}", x))?
-        } else {
-            path
+    pub fn epoch(&self) -> u64 {
+        self.inodes.epoch()
+    }
+
+    /// A read-only view of the current epoch, which outlives the switch to the next one
+    pub fn pin(&self) -> Result<Self> {
+        Ok(Self {
+            root_path: self.root_path.clone(),
+            root: self.root,
+            inodes: self.inodes.pin()?,
+            scanner: self.scanner.clone(),
+        })
+    }
+
+    pub fn maintenance(&self) -> Option<Box<dyn Maintenance>> {
+        self.inodes.maintenance()
+    }
+
+    /// Scan the whole tree under root_path into storage.
+    /// The root inode is written last, so an interrupted scan never looks complete.
+    pub fn populate(&mut self) -> Result<()> {
+        let root_metadata = std::fs::metadata(&self.root_path)?;
+        self.root = root_metadata.ino();
+        let contents = self
+            .scanner
+            .scan(
+                self.inodes.as_mut(),
+                &self.root_path,
+                Path::new(""),
+                &root_metadata,
+                INVALID_PARENT,
+            )?
+            .ok_or_else(|| anyhow!("Not a directory: {}", self.root_path.display()))?;
+        self.update(Inode::new(root_metadata, contents))
+    }
+
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    pub fn scanner(&self) -> Arc<Scanner> {
+        self.scanner.clone()
+    }
+
+    /// Replace the cached subtree at scan's path with the scanned one, and fix up its parent entry.
+    /// Everything outside of the subtree is left alone.
+    pub fn splice(&mut self, scan: SubtreeScan) -> Result<()> {
+        let parent = self.open(&scan.parent_path)?;
+        let mut directory = match parent.contents {
+            Contents::Directory(contents) => contents,
+            _ => return Err(anyhow!("Not a directory: {}", scan.parent_path.display())),
         };
-        for component in relative.components() {
-            match component {
-                Component::Prefix(_) => unreachable!(),
-                Component::RootDir => continue,
-                Component::CurDir => continue,
-                Component::ParentDir => {
-                    let parent_id = match inode.contents {
-                        Contents::Directory(ref contents) => contents.parent,
-                        _ => unreachable!(),
-                    };
-                    if parent_id == INVALID_PARENT {
-                        return Err(anyhow!("Invalid path: {}", path.display()));
-                    }
-                    inode = self.inodes.get(&parent_id).unwrap();
+        if parent.id != scan.parent_metadata.ino() {
+            return Err(anyhow!(
+                "Directory {} has been replaced on disk, refresh its parent instead",
+                scan.parent_path.display()
+            ));
+        }
+
+        let position = directory
+            .children
+            .iter()
+            .position(|item| item.name == scan.name);
+        let stale = match position {
+            Some(position) => {
+                self.collect_subtree(parent.id, &scan.name, directory.children[position].inode)?
+            }
+            None => Vec::new(),
+        };
+
+        // readers see either the old subtree or the new one
+        let mut inodes = Transaction::begin(self.inodes.as_mut())?;
+        for id in stale {
+            if scan.inodes.get(&id)?.is_none() {
+                inodes.remove(&id)?;
+            }
+        }
+        inodes.insert_many(
+            scan.inodes
+                .values()
+                .into_iter()
+                .map(|inode| (inode.id, inode))
+                .collect(),
+        )?;
+
+        let item = scan.inode.as_ref().map(|inode| DirectoryItem {
+            name: scan.name.clone(),
+            inode: inode.id,
+            itype: inode.itype(),
+        });
+        match (position, item) {
+            (Some(position), Some(item)) => directory.children[position] = item,
+            (Some(position), None) => {
+                directory.children.remove(position);
+            }
+            (None, Some(item)) => directory.children.push(item),
+            (None, None) => {}
+        }
+        if let Some(inode) = scan.inode {
+            inodes.insert(inode.id, inode)?;
+        }
+        let parent = Inode::new(scan.parent_metadata, Contents::Directory(directory));
+        inodes.insert(parent.id, parent)?;
+        inodes.commit()
+    }
+
+    /// Collect ids of inode id, which is entry name of directory parent, and of everything below it.
+    /// Files with other hard links are skipped if one of them is outside of the subtree,
+    /// as they are still reachable from there.
+    fn collect_subtree(&self, parent: InodeId, name: &str, id: InodeId) -> Result<Vec<InodeId>> {
+        let mut ids = Vec::new();
+        let mut linked = Vec::new();
+        let mut directories = HashSet::new();
+        let mut pending = vec![id];
+        while let Some(id) = pending.pop() {
+            let inode = match self.inodes.get(&id)? {
+                Some(inode) => inode,
+                None => continue,
+            };
+            match inode.contents {
+                Contents::Directory(ref contents) => {
+                    directories.insert(id);
+                    pending.extend(contents.children.iter().map(|item| item.inode));
+                    ids.push(id);
                 }
-                Component::Normal(name) => {
-                    // handling symlink dir
-                    let mut redirection = 0;
-                    while let Contents::Symlink(ref target) = inode.contents {
-                        if redirection > RECURSIVE_LIMIT {
-                            return Err(anyhow!("Too many redirections"));
-                        }
-                        let target_path = Path::new(target);
-                        let target_inode = self.open(target_path)?;
-                        inode = target_inode;
-                        redirection += 1;
-                    }
-                    let directory = match inode.contents {
-                        Contents::Directory(ref contents) => contents,
-                        _ => unreachable!(),
-                    };
-                    let mut found = false;
-                    for item in &directory.children {
-                        if item.name == name.to_string_lossy() {
-                            found = true;
-                            inode = self.inodes.get(&item.inode).unwrap();
-                            break;
+                _ if inode.nlink > 1 => linked.push(id),
+                _ => ids.push(id),
+            }
+        }
+        linked.sort_unstable();
+        linked.dedup();
+        for id in linked {
+            let outside = self.inodes.links(id)?.into_iter().any(|(dir, entry)| {
+                !directories.contains(&dir) && (dir != parent || entry != name)
+            });
+            if !outside {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    pub fn update(&mut self, inode: Inode) -> Result<()> {
+        self.inodes.insert(inode.id, inode)
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        if path.is_absolute() {
+            path.strip_prefix(&self.root_path).map_err(|x| {
+                BuhaoError::new(ErrorKind::Unmanaged, format!("Unmanaged path: {}", x)).into()
+            })
+        } else {
+            Ok(path)
+        }
+    }
+
+    /// Names of the entries in the directory at path
+    pub fn children(&self, path: &Path) -> Result<Vec<String>> {
+        match self.open(path)?.contents {
+            Contents::Directory(contents) => Ok(contents
+                .children
+                .into_iter()
+                .map(|item| item.name)
+                .collect()),
+            _ => Err(BuhaoError::new(
+                ErrorKind::NotADirectory,
+                format!("Not a directory: {}", path.display()),
+            )
+            .into()),
+        }
+    }
+
+    /// List path and every directory below it, relative to root_path. Symlinks are not followed.
+    pub fn directories(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let inode = self.open(path)?;
+        if !matches!(inode.contents, Contents::Directory(_)) {
+            return Ok(Vec::new());
+        }
+        let mut directories = Vec::new();
+        let mut stack = vec![(self.relative(path)?.to_path_buf(), inode)];
+        while let Some((path, inode)) = stack.pop() {
+            if let Contents::Directory(ref contents) = inode.contents {
+                for item in &contents.children {
+                    if let InodeType::Directory = item.itype {
+                        if let Some(child) = self.inodes.get(&item.inode)? {
+                            stack.push((path.join(&item.name), child));
                         }
                     }
-                    if !found {
-                        return Err(anyhow!("Invalid path: {}", path.display()));
-                    }
                 }
             }
+            directories.push(path);
         }
-        Ok(inode)
+        Ok(directories)
     }
-}
 
-pub fn dfs_list(filesystem: &mut Filesystem, dir: &Path) -> Result<Vec<DirectoryItem>> {
-    let self_id = std::fs::metadata(dir)?.ino();
-    let paths = std::fs::read_dir(dir)?;
-    let mut items = Vec::new();
-    for path in paths {
-        let path = match path {
-            Ok(path) => path,
-            Err(ref e) => {
-                warn!("Failed to read directory {:?} entry: {}", path, e);
+    /// Look up path without following a symlink at the end, like lstat(2)
+    pub fn open(&self, path: &Path) -> Result<Inode> {
+        self.resolve(path, false)
+    }
+
+    /// Look up path like resolve, for clients.
+    /// Directories come without their entries, which are not even read, and are listed a page at a time by readdir.
+    pub fn stat(&self, path: &Path, follow: bool) -> Result<Inode> {
+        let (id, _) = self.walk(path, follow)?;
+        self.inodes
+            .get_shallow(id)?
+            .ok_or_else(|| not_found(path).into())
+    }
+
+    /// The inode with id, without the entries of a directory like stat.
+    /// It is stale if it is not in this epoch anymore, like the NFS handle of a deleted file,
+    /// or if its generation is not the one given, as the number has been taken by another inode.
+    /// Generations are only compared where both are known.
+    pub fn get_by_inode(&self, id: InodeId, generation: Option<(i64, i64)>) -> Result<Inode> {
+        self.inodes
+            .get_shallow(id)?
+            .filter(|inode| match (generation, inode.generation()) {
+                (Some(expected), Some(actual)) => expected == actual,
+                _ => true,
+            })
+            .ok_or_else(|| BuhaoError::new(ErrorKind::Stale, format!("Stale inode: {}", id)).into())
+    }
+
+    /// Every path of the inode with id relative to root_path, one for each hard link, in order.
+    /// The root itself is the empty path. Fails as stale like get_by_inode.
+    pub fn paths(&self, id: InodeId, generation: Option<(i64, i64)>) -> Result<Vec<String>> {
+        self.get_by_inode(id, generation)?;
+        let mut paths = Vec::new();
+        // each inode with the names walked so far up to it, the last one first
+        let mut pending = vec![(id, Vec::new())];
+        while let Some((id, names)) = pending.pop() {
+            if id == self.root {
+                let names: Vec<String> = names.into_iter().rev().collect();
+                paths.push(names.join("/"));
                 continue;
             }
+            if names.len() > MAX_DEPTH {
+                return Err(anyhow!("Path of inode {} is too deep", id));
+            }
+            // directories have a single entry, so only the links of the inode itself branch out
+            for (parent, name) in self.inodes.links(id)? {
+                let mut names = names.clone();
+                names.push(name);
+                pending.push((parent, names));
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Up to limit entries of the directory at path, ordered by name and starting after the after cursor.
+    /// A symlink at the end is followed, like opendir(3).
+    pub fn readdir(&self, path: &Path, after: &str, limit: usize) -> Result<DirectoryPage> {
+        let (id, itype) = self.walk(path, true)?;
+        if !matches!(itype, InodeType::Directory) {
+            return Err(BuhaoError::new(
+                ErrorKind::NotADirectory,
+                format!("Not a directory: {}", path.display()),
+            )
+            .into());
+        }
+        // one more than asked, to tell whether there is a next page
+        let mut entries = self.inodes.readdir(id, after, limit + 1)?;
+        let next = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|item| item.name.clone())
+        } else {
+            None
         };
-        let metadata = match path.metadata() {
-            Ok(metadata) => metadata,
-            Err(ref e) => {
-                warn!("Failed to read {:?} metadata: {}", path, e);
+        Ok(DirectoryPage { entries, next })
+    }
+
+    /// Look up path (absolute, or relative to root_path) with POSIX semantics.
+    /// Symlinks in the middle are always followed, and the last one only if follow is set.
+    /// Relative targets start from the directory of the link, and absolute ones must be under root_path.
+    pub fn resolve(&self, path: &Path, follow: bool) -> Result<Inode> {
+        let (id, _) = self.walk(path, follow)?;
+        self.inodes.get(&id)?.ok_or_else(|| not_found(path).into())
+    }
+
+    /// Walk path like resolve, to the id and type of the inode it ends at, without reading the inode itself.
+    /// Entries left out by the filter are unmanaged rather than missing, as they may well exist on disk.
+    fn walk(&self, path: &Path, follow: bool) -> Result<(InodeId, InodeType)> {
+        let relative = self.relative(path)?;
+        // components left to walk, in reverse order, so that symlink targets are pushed in front
+        let mut pending: Vec<OsString> = Vec::new();
+        push_components(&mut pending, relative, ends_with_slash(path));
+        // invariant: id is always a directory, except after the last component.
+        // Entries are looked up one by one, so intermediate directories are never loaded whole.
+        let mut id = self.root;
+        let mut itype = InodeType::Directory;
+        // path of id relative to root_path, as the filter sees it
+        let mut directory = PathBuf::new();
+        let mut links = 0;
+        while let Some(name) = pending.pop() {
+            if !matches!(itype, InodeType::Directory) {
+                return Err(BuhaoError::new(
+                    ErrorKind::NotADirectory,
+                    format!("Not a directory: {}", path.display()),
+                )
+                .into());
+            }
+            if name == ".." {
+                let parent_id = self.inodes.parent(id)?.ok_or_else(|| not_found(path))?;
+                if parent_id == INVALID_PARENT {
+                    return Err(BuhaoError::new(
+                        ErrorKind::OutsideRoot,
+                        format!("Path escapes the root: {}", path.display()),
+                    )
+                    .into());
+                }
+                id = parent_id;
+                directory.pop();
                 continue;
             }
-        };
-        let filetype = metadata.file_type();
-        let contents = {
-            if filetype.is_symlink() {
-                let target = match std::fs::read_link(path.path()) {
-                    Ok(target) => target,
-                    Err(ref e) => {
-                        warn!("Failed to read symlink {:?} target: {}", path, e);
-                        continue;
+            // only left by a trailing slash, once id is known to be a directory
+            if name == "." {
+                continue;
+            }
+            let item = match self.inodes.lookup(id, &name.to_string_lossy())? {
+                Some(item) => item,
+                None => {
+                    let entry = directory.join(&name);
+                    let filter = self.scanner.filter();
+                    // whether it is a directory is not cached, so either way
+                    if filter.is_excluded(&entry, false) || filter.is_excluded(&entry, true) {
+                        return Err(BuhaoError::new(
+                            ErrorKind::Unmanaged,
+                            format!("Excluded path: {}", path.display()),
+                        )
+                        .into());
                     }
-                };
-                Contents::Symlink(target.to_string_lossy().to_string())
-            } else if filetype.is_file() {
-                Contents::File
-            } else if filetype.is_dir() {
-                let children = match dfs_list(filesystem, path.path().as_path()) {
-                    Ok(children) => children,
-                    Err(ref e) => {
-                        warn!("Failed to read directory {:?} contents: {}", path, e);
-                        continue;
+                    return Err(not_found(path).into());
+                }
+            };
+            if let InodeType::Symlink = item.itype {
+                if follow || !pending.is_empty() {
+                    links += 1;
+                    if links > RECURSIVE_LIMIT {
+                        return Err(BuhaoError::new(
+                            ErrorKind::Loop,
+                            format!("Too many levels of symbolic links: {}", path.display()),
+                        )
+                        .into());
                     }
-                };
-                Contents::Directory(DirectoryContents {
-                    parent: self_id,
-                    children,
-                })
-            } else {
-                continue;
+                    let target = match self.inodes.get(&item.inode)?.map(|inode| inode.contents) {
+                        Some(Contents::Symlink(target)) => PathBuf::from(target),
+                        _ => return Err(not_found(path).into()),
+                    };
+                    let trailing = ends_with_slash(&target);
+                    if target.is_absolute() {
+                        let target = target.strip_prefix(&self.root_path).map_err(|_| {
+                            BuhaoError::new(
+                                ErrorKind::OutsideRoot,
+                                format!(
+                                    "Symlink escapes the root: {} -> {}",
+                                    path.display(),
+                                    target.display()
+                                ),
+                            )
+                        })?;
+                        push_components(&mut pending, target, trailing);
+                        id = self.root;
+                        directory = PathBuf::new();
+                    } else {
+                        // resolved from the directory holding the link, which is id
+                        push_components(&mut pending, &target, trailing);
+                    }
+                    continue;
+                }
             }
-        };
-        let id = metadata.ino();
-        items.push(DirectoryItem {
-            name: path.file_name().as_os_str().to_string_lossy().to_string(),
-            inode: id,
-            itype: match contents {
-                Contents::File => InodeType::File,
-                Contents::Directory(_) => InodeType::Directory,
-                Contents::Symlink(_) => InodeType::Symlink,
-            },
-        });
-        filesystem.update(Inode::new(metadata, contents));
+            id = item.inode;
+            itype = item.itype;
+            directory.push(&name);
+        }
+        Ok((id, itype))
     }
+}
 
-    Ok(items)
+/// Components of a path found by paths, beyond which the cache is taken as broken instead of looping
+const MAX_DEPTH: usize = 4096;
+
+fn not_found(path: &Path) -> BuhaoError {
+    BuhaoError::new(
+        ErrorKind::NotFound,
+        format!("Invalid path: {}", path.display()),
+    )
+}
+
+/// Whether path ends with a slash, which components() leaves out
+fn ends_with_slash(path: &Path) -> bool {
+    path.as_os_str().as_bytes().ends_with(b"/")
+}
+
+/// Push the components of path onto a stack of pending components, the first one on top.
+/// A trailing slash is kept as a last "." component, for the entry before it must be a directory,
+/// and a symlink there is followed like one in the middle.
+fn push_components(pending: &mut Vec<OsString>, path: &Path, trailing: bool) {
+    if trailing {
+        pending.push(OsString::from("."));
+    }
+    let components = path.components().filter_map(|component| match component {
+        Component::Normal(name) => Some(name.to_os_string()),
+        Component::ParentDir => Some(OsString::from("..")),
+        Component::Prefix(_) | Component::RootDir | Component::CurDir => None,
+    });
+    let start = pending.len();
+    pending.extend(components);
+    pending[start..].reverse();
+}
+
+/// A subtree scanned from disk, waiting to be spliced into the cache
+#[derive(Debug)]
+pub struct SubtreeScan {
+    /// Path of the parent directory, relative to root_path
+    parent_path: PathBuf,
+    parent_metadata: std::fs::Metadata,
+    name: String,
+    /// None if the path no longer exists on disk
+    inode: Option<Inode>,
+    inodes: StdHashMap,
+}
+
+impl SubtreeScan {
+    /// Directories in the scanned subtree, relative to root_path. Empty if it is not a directory.
+    pub fn directories(&self) -> Result<Vec<PathBuf>> {
+        let mut directories = Vec::new();
+        let mut stack = Vec::new();
+        if let Some(ref inode) = self.inode {
+            stack.push((self.parent_path.join(&self.name), inode.clone()));
+        }
+        while let Some((path, inode)) = stack.pop() {
+            if let Contents::Directory(ref contents) = inode.contents {
+                for item in &contents.children {
+                    if let InodeType::Directory = item.itype {
+                        if let Some(child) = self.inodes.get(&item.inode)? {
+                            stack.push((path.join(&item.name), child));
+                        }
+                    }
+                }
+                directories.push(path);
+            }
+        }
+        Ok(directories)
+    }
+}
+
+/// Rescan `path` (absolute, or relative to `root_path`) into a staging map without touching the cache
+pub fn scan_subtree(root_path: &Path, scanner: &Scanner, path: &Path) -> Result<SubtreeScan> {
+    let absolute = root_path.join(path);
+    let relative = absolute
+        .strip_prefix(root_path)
+        .map_err(|x| BuhaoError::new(ErrorKind::Unmanaged, format!("Unmanaged path: {}", x)))?;
+    let mut components = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => components.push(name),
+            Component::CurDir => continue,
+            _ => {
+                return Err(BuhaoError::new(
+                    ErrorKind::InvalidRequest,
+                    format!("Invalid refresh path: {}", path.display()),
+                )
+                .into())
+            }
+        }
+    }
+    let name = match components.pop() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => {
+            return Err(BuhaoError::new(
+                ErrorKind::InvalidRequest,
+                format!("Subtree refresh on the root: {}", path.display()),
+            )
+            .into())
+        }
+    };
+    let parent_path: PathBuf = components.iter().collect();
+    let relative = parent_path.join(&name);
+    let disk_path = root_path.join(&relative);
+    let parent_metadata = std::fs::metadata(root_path.join(&parent_path))?;
+
+    let mut inodes = StdHashMap::new();
+    let inode = match std::fs::symlink_metadata(&disk_path) {
+        // an excluded entry is scanned as if it is gone
+        Ok(metadata) if scanner.filter().is_excluded(&relative, metadata.is_dir()) => None,
+        Ok(metadata) => scanner
+            .scan(
+                &mut inodes,
+                &disk_path,
+                &relative,
+                &metadata,
+                parent_metadata.ino(),
+            )?
+            .map(|contents| Inode::new(metadata, contents)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    Ok(SubtreeScan {
+        parent_path,
+        parent_metadata,
+        name,
+        inode,
+        inodes,
+    })
 }
 
 #[cfg(test)]
 mod test {
-    use std::{fs::File, io::Write, os::unix::fs::symlink};
+    use std::{
+        fs::File,
+        io::Write,
+        os::unix::fs::{symlink, MetadataExt, PermissionsExt},
+    };
 
     use super::*;
+    use crate::filter::Filter;
+    use buhao_lib::Change;
     use test_log::test;
 
+    fn scanner() -> Scanner {
+        Scanner::new(Filter::default(), 0).unwrap()
+    }
+
     /// Setup /tmp/buhao
     /// ├── a
     /// └── b
@@ -227,7 +632,7 @@ mod test {
 
     #[test]
     fn test_open() {
-        let filesystem = Filesystem::new_from_fs(Path::new("/tmp/buhao"));
+        let filesystem = Filesystem::new_from_fs(Path::new("/tmp/buhao"), scanner()).unwrap();
         println!(
             "root path: {:?}, root: {}",
             filesystem.root_path, filesystem.root
@@ -246,4 +651,499 @@ mod test {
         let inode = filesystem.open(Path::new("./b/c")).unwrap();
         println!("./b/c: {:?}", inode);
     }
+
+    #[test]
+    fn test_resolve() {
+        let root = Path::new("/tmp/buhao-resolve");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::create_dir_all(root.join("d/e")).unwrap();
+        File::create(root.join("d/f")).unwrap();
+        symlink("f", root.join("d/rel")).unwrap();
+        symlink("../../d/f", root.join("d/e/up")).unwrap();
+        symlink("/tmp/buhao-resolve/d/f", root.join("abs")).unwrap();
+        symlink("d", root.join("dir")).unwrap();
+        symlink("..", root.join("d/parent")).unwrap();
+        symlink("../../etc", root.join("d/escape")).unwrap();
+        symlink("/etc", root.join("escape")).unwrap();
+        symlink("loop2", root.join("loop1")).unwrap();
+        symlink("loop1", root.join("loop2")).unwrap();
+        let filesystem = Filesystem::new_from_fs(root, scanner()).unwrap();
+        let file = filesystem.open(Path::new("d/f")).unwrap().id;
+        let resolve = |path: &str, follow| filesystem.resolve(Path::new(path), follow);
+
+        // the last component is only followed when asked to
+        assert!(matches!(
+            resolve("d/rel", false).unwrap().contents,
+            Contents::Symlink(_)
+        ));
+        assert_eq!(resolve("d/rel", true).unwrap().id, file);
+        // relative targets start from the directory of the link
+        assert_eq!(resolve("d/e/up", true).unwrap().id, file);
+        assert_eq!(resolve("abs", true).unwrap().id, file);
+        // links in the middle are always followed
+        assert_eq!(resolve("dir/f", false).unwrap().id, file);
+        assert_eq!(resolve("d/parent/d/parent/dir/rel", true).unwrap().id, file);
+        assert_eq!(
+            resolve("/tmp/buhao-resolve/dir/e/../f", false).unwrap().id,
+            file
+        );
+
+        let error = |path: &str| resolve(path, true).unwrap_err().to_string();
+        assert!(error("loop1").starts_with("Too many levels of symbolic links"));
+        assert!(error("d/escape").starts_with("Path escapes the root"));
+        assert!(error("escape").starts_with("Symlink escapes the root"));
+        assert!(error("..").starts_with("Path escapes the root"));
+        assert!(error("d/f/x").starts_with("Not a directory"));
+        // a trailing slash asks for a directory, following a link to one even with lstat
+        assert_eq!(
+            resolve("dir/", false).unwrap().id,
+            filesystem.open(Path::new("d")).unwrap().id
+        );
+        assert_eq!(
+            resolve("/tmp/buhao-resolve/dir/", false).unwrap().id,
+            filesystem.open(Path::new("d")).unwrap().id
+        );
+        assert!(error("d/f/").starts_with("Not a directory"));
+        assert!(error("d/rel/").starts_with("Not a directory"));
+        assert!(error("d/missing").starts_with("Invalid path"));
+        // and the kinds sent to clients
+        let kind = |path: &str| BuhaoError::from_anyhow(&resolve(path, true).unwrap_err()).kind;
+        assert_eq!(kind("loop1"), ErrorKind::Loop);
+        assert_eq!(kind("escape"), ErrorKind::OutsideRoot);
+        assert_eq!(kind("d/f/x"), ErrorKind::NotADirectory);
+        assert_eq!(kind("d/f/"), ErrorKind::NotADirectory);
+        assert_eq!(kind("/elsewhere"), ErrorKind::Unmanaged);
+        let missing = BuhaoError::from_anyhow(&resolve("d/missing", true).unwrap_err());
+        assert_eq!(
+            serde_json::to_value(&missing).unwrap(),
+            serde_json::json!({
+                "kind": "not_found",
+                "errno": ErrorKind::NotFound.errno(),
+                "message": "Invalid path: d/missing",
+            })
+        );
+        // a dangling link is fine unless followed
+        assert!(resolve("escape", false).is_ok());
+    }
+
+    #[test]
+    fn test_refresh() {
+        let root = Path::new("/tmp/buhao-refresh");
+        let db = Path::new("/tmp/buhao-refresh.db");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        std::fs::create_dir_all(root.join("d")).unwrap();
+
+        let filesystem = Filesystem::new_from_sqlite("test", root, db, scanner()).unwrap();
+        assert!(filesystem.open(Path::new("./d/new")).is_err());
+
+        File::create(root.join("d/new")).unwrap();
+        let mut next = filesystem.next_epoch().unwrap();
+        assert!(next.epoch() > filesystem.epoch());
+        next.populate().unwrap();
+        // old epoch is still served as it was
+        assert!(filesystem.open(Path::new("./d/new")).is_err());
+        assert!(next.open(Path::new("./d/new")).is_ok());
+
+        // reopening the database picks up the newest complete epoch
+        let epoch = next.epoch();
+        drop(next);
+        drop(filesystem);
+        let filesystem = Filesystem::new_from_sqlite("test", root, db, scanner()).unwrap();
+        assert_eq!(filesystem.epoch(), epoch);
+        assert!(filesystem.open(Path::new("./d/new")).is_ok());
+    }
+
+    #[test]
+    fn test_subtree_refresh() {
+        let root = Path::new("/tmp/buhao-subtree");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::create_dir_all(root.join("x/y")).unwrap();
+        std::fs::create_dir_all(root.join("z")).unwrap();
+        File::create(root.join("x/y/old")).unwrap();
+
+        let mut filesystem = Filesystem::new_from_fs(root, scanner()).unwrap();
+
+        std::fs::remove_file(root.join("x/y/old")).unwrap();
+        File::create(root.join("x/y/new")).unwrap();
+        File::create(root.join("z/untouched")).unwrap();
+        std::fs::create_dir_all(root.join("w")).unwrap();
+
+        filesystem
+            .splice(scan_subtree(root, &scanner(), Path::new("x")).unwrap())
+            .unwrap();
+        assert!(filesystem.open(Path::new("./x/y/new")).is_ok());
+        assert!(filesystem.open(Path::new("./x/y/old")).is_err());
+        // root, x, y, new and z, with the stale inode gone
+        assert_eq!(filesystem.inodes.values().len(), 5);
+        // outside of the subtree nothing changes
+        assert!(filesystem.open(Path::new("./z/untouched")).is_err());
+
+        // new and deleted entries are added to and removed from the parent
+        filesystem
+            .splice(scan_subtree(root, &scanner(), Path::new("/tmp/buhao-subtree/w")).unwrap())
+            .unwrap();
+        assert!(filesystem.open(Path::new("./w")).is_ok());
+        std::fs::remove_dir_all(root.join("w")).unwrap();
+        filesystem
+            .splice(scan_subtree(root, &scanner(), Path::new("w")).unwrap())
+            .unwrap();
+        assert!(filesystem.open(Path::new("./w")).is_err());
+    }
+
+    #[test]
+    fn test_hard_link_refresh() {
+        let root = Path::new("/tmp/buhao-hardlink");
+        let db = Path::new("/tmp/buhao-hardlink.db");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        std::fs::create_dir_all(root.join("d")).unwrap();
+        std::fs::create_dir_all(root.join("e")).unwrap();
+        File::create(root.join("d/f")).unwrap();
+        std::fs::hard_link(root.join("d/f"), root.join("g")).unwrap();
+        File::create(root.join("e/a")).unwrap();
+        std::fs::hard_link(root.join("e/a"), root.join("e/b")).unwrap();
+        let outside = std::fs::metadata(root.join("g")).unwrap().ino();
+        let inside = std::fs::metadata(root.join("e/a")).unwrap().ino();
+        let mut backends = [
+            Filesystem::new_from_fs(root, scanner()).unwrap(),
+            Filesystem::new_from_sqlite("test", root, db, scanner()).unwrap(),
+        ];
+        let refresh = |backends: &mut [Filesystem], path: &str| {
+            for filesystem in backends {
+                filesystem
+                    .splice(scan_subtree(root, &scanner(), Path::new(path)).unwrap())
+                    .unwrap();
+            }
+        };
+        let stale = |filesystem: &Filesystem, id| {
+            let error = filesystem.get_by_inode(id, None).unwrap_err();
+            BuhaoError::from_anyhow(&error).kind == ErrorKind::Stale
+        };
+
+        // still linked from outside of the removed subtree
+        std::fs::remove_dir_all(root.join("d")).unwrap();
+        refresh(&mut backends, "d");
+        for filesystem in &backends {
+            assert_eq!(filesystem.paths(outside, None).unwrap(), vec!["g"]);
+        }
+        // every link is gone
+        std::fs::remove_file(root.join("g")).unwrap();
+        refresh(&mut backends, "g");
+        std::fs::remove_dir_all(root.join("e")).unwrap();
+        refresh(&mut backends, "e");
+        for filesystem in &backends {
+            assert!(stale(filesystem, outside));
+            assert!(stale(filesystem, inside));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_read() {
+        let root = Path::new("/tmp/buhao-concurrent");
+        let db = Path::new("/tmp/buhao-concurrent.db");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        for i in 0..100 {
+            std::fs::create_dir_all(root.join(format!("d{}", i))).unwrap();
+        }
+
+        let filesystem = std::sync::Arc::new(std::sync::RwLock::new(
+            Filesystem::new_from_sqlite("test", root, db, scanner()).unwrap(),
+        ));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let filesystem = filesystem.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        let path = format!("./d{}", i);
+                        assert!(filesystem.read().unwrap().open(Path::new(&path)).is_ok());
+                    }
+                })
+            })
+            .collect();
+        // a new epoch is written while lookups go on
+        let mut next = filesystem.read().unwrap().next_epoch().unwrap();
+        next.populate().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        *filesystem.write().unwrap() = next;
+        assert!(filesystem.read().unwrap().open(Path::new("./d99")).is_ok());
+    }
+
+    #[test]
+    fn test_filter() {
+        let root = Path::new("/tmp/buhao-filter");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::create_dir_all(root.join("pool/.~tmp~")).unwrap();
+        std::fs::create_dir_all(root.join("keep/.~tmp~")).unwrap();
+        File::create(root.join("pool/.~tmp~/partial")).unwrap();
+        File::create(root.join("pool/db.lock")).unwrap();
+        File::create(root.join("pool/file")).unwrap();
+
+        let filter = Filter::parse(&["+ /keep/.~tmp~/", "- .~tmp~/", "- *.lock"]).unwrap();
+        let mut filesystem =
+            Filesystem::new_from_fs(root, Scanner::new(filter, 0).unwrap()).unwrap();
+        assert!(filesystem.open(Path::new("./pool/file")).is_ok());
+        assert!(filesystem.open(Path::new("./pool/.~tmp~")).is_err());
+        assert!(filesystem.open(Path::new("./pool/.~tmp~/partial")).is_err());
+        assert!(filesystem.open(Path::new("./pool/db.lock")).is_err());
+        assert!(filesystem.open(Path::new("./keep/.~tmp~")).is_ok());
+        // excluded entries are not known to be missing, unlike the others
+        let kind = |path: &str| {
+            BuhaoError::from_anyhow(&filesystem.open(Path::new(path)).unwrap_err()).kind
+        };
+        assert_eq!(kind("pool/.~tmp~/partial"), ErrorKind::Unmanaged);
+        assert_eq!(kind("keep/../pool/db.lock"), ErrorKind::Unmanaged);
+        assert_eq!(kind("pool/missing"), ErrorKind::NotFound);
+
+        // excluded entries are skipped by subtree refreshes as well
+        File::create(root.join("pool/new.lock")).unwrap();
+        let scanner = filesystem.scanner();
+        filesystem
+            .splice(scan_subtree(root, &scanner, Path::new("pool")).unwrap())
+            .unwrap();
+        assert!(filesystem.open(Path::new("./pool/new.lock")).is_err());
+        let scan = scan_subtree(root, &scanner, Path::new("pool/new.lock")).unwrap();
+        assert!(scan.inode.is_none());
+    }
+
+    #[test]
+    fn test_filter_changed() {
+        let root = Path::new("/tmp/buhao-filter-changed");
+        let db = Path::new("/tmp/buhao-filter-changed.db");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        std::fs::create_dir_all(root).unwrap();
+        File::create(root.join("db.lock")).unwrap();
+        let open = |rules: &[&str]| {
+            let scanner = Scanner::new(Filter::parse(rules).unwrap(), 0).unwrap();
+            Filesystem::new_from_sqlite("test", root, db, scanner).unwrap()
+        };
+
+        let filesystem = open(&["- *.lock"]);
+        let epoch = filesystem.epoch();
+        assert!(filesystem.open(Path::new("db.lock")).is_err());
+        // the same rules reuse the last epoch after a restart
+        let filesystem = open(&["- *.lock"]);
+        assert_eq!(filesystem.epoch(), epoch);
+        // other ones scan again
+        let filesystem = open(&["- *.tmp"]);
+        assert!(filesystem.epoch() > epoch);
+        assert!(filesystem.open(Path::new("db.lock")).is_ok());
+        let filesystem = open(&["- *.tmp"]);
+        assert!(filesystem.open(Path::new("db.lock")).is_ok());
+    }
+
+    #[test]
+    fn test_readdir() {
+        let root = Path::new("/tmp/buhao-readdir");
+        let db = Path::new("/tmp/buhao-readdir.db");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        std::fs::create_dir_all(root.join("d")).unwrap();
+        for i in 0..25 {
+            File::create(root.join(format!("d/{:02}", i))).unwrap();
+        }
+        symlink("d", root.join("link")).unwrap();
+        let mut backends = [
+            Filesystem::new_from_fs(root, scanner()).unwrap(),
+            Filesystem::new_from_sqlite("test", root, db, scanner()).unwrap(),
+        ];
+        for filesystem in &backends {
+            // stat leaves the entries to readdir
+            match filesystem.stat(Path::new("d"), false).unwrap().contents {
+                Contents::Directory(contents) => assert!(contents.children.is_empty()),
+                _ => panic!("Not a directory"),
+            }
+
+            let mut names = Vec::new();
+            let mut after = String::new();
+            loop {
+                let page = filesystem.readdir(Path::new("link"), &after, 10).unwrap();
+                assert!(page.entries.len() <= 10);
+                names.extend(page.entries.into_iter().map(|item| item.name));
+                match page.next {
+                    Some(next) => after = next,
+                    None => break,
+                }
+            }
+            let expected: Vec<_> = (0..25).map(|i| format!("{:02}", i)).collect();
+            assert_eq!(names, expected);
+
+            let page = filesystem.readdir(Path::new("d"), "19", 10).unwrap();
+            assert_eq!(page.entries.len(), 5);
+            assert!(page.next.is_none());
+            let kind = BuhaoError::from_anyhow(
+                &filesystem.readdir(Path::new("d/00"), "", 10).unwrap_err(),
+            )
+            .kind;
+            assert_eq!(kind, ErrorKind::NotADirectory);
+        }
+
+        // pages follow the entries of refreshed directories
+        std::fs::remove_file(root.join("d/10")).unwrap();
+        File::create(root.join("d/25")).unwrap();
+        for filesystem in &mut backends {
+            filesystem
+                .splice(scan_subtree(root, &scanner(), Path::new("d")).unwrap())
+                .unwrap();
+            let page = filesystem.readdir(Path::new("d"), "09", 2).unwrap();
+            let names: Vec<_> = page.entries.into_iter().map(|item| item.name).collect();
+            assert_eq!(names, vec!["11", "12"]);
+            let page = filesystem.readdir(Path::new("d"), "24", 2).unwrap();
+            assert_eq!(page.entries[0].name, "25");
+        }
+    }
+
+    #[test]
+    fn test_paths() {
+        let root = Path::new("/tmp/buhao-paths");
+        let db = Path::new("/tmp/buhao-paths.db");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        std::fs::create_dir_all(root.join("d/e")).unwrap();
+        File::create(root.join("d/e/f")).unwrap();
+        std::fs::hard_link(root.join("d/e/f"), root.join("g")).unwrap();
+        std::fs::hard_link(root.join("d/e/f"), root.join("d/h")).unwrap();
+        let file = std::fs::metadata(root.join("g")).unwrap().ino();
+        let metadata = std::fs::metadata(root.join("d/e")).unwrap();
+        let dir = metadata.ino();
+        let generation = Inode::new(metadata, Contents::File).generation().unwrap();
+        let mut backends = [
+            Filesystem::new_from_fs(root, scanner()).unwrap(),
+            Filesystem::new_from_sqlite("test", root, db, scanner()).unwrap(),
+        ];
+        for filesystem in &backends {
+            assert_eq!(
+                filesystem.paths(file, None).unwrap(),
+                vec!["d/e/f", "d/h", "g"]
+            );
+            assert_eq!(filesystem.paths(dir, None).unwrap(), vec!["d/e"]);
+            assert_eq!(filesystem.paths(filesystem.root, None).unwrap(), vec![""]);
+            let kind =
+                BuhaoError::from_anyhow(&filesystem.paths(i64::MAX as u64, None).unwrap_err()).kind;
+            assert_eq!(kind, ErrorKind::Stale);
+            // an inode of another generation is stale as well, as it only took the number
+            assert!(filesystem.get_by_inode(dir, Some(generation)).is_ok());
+            let error = filesystem
+                .get_by_inode(dir, Some((generation.0, generation.1 + 1)))
+                .unwrap_err();
+            assert_eq!(BuhaoError::from_anyhow(&error).kind, ErrorKind::Stale);
+        }
+
+        // the index follows the entries of updated directories
+        std::fs::remove_file(root.join("g")).unwrap();
+        for filesystem in &mut backends {
+            filesystem
+                .splice(scan_subtree(root, &scanner(), Path::new("g")).unwrap())
+                .unwrap();
+            assert_eq!(filesystem.paths(file, None).unwrap(), vec!["d/e/f", "d/h"]);
+        }
+    }
+
+    #[test]
+    fn test_generation() {
+        let root = Path::new("/tmp/buhao-generation");
+        let db = Path::new("/tmp/buhao-generation.db");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        std::fs::create_dir_all(root).unwrap();
+        File::create(root.join("f")).unwrap();
+        let metadata = std::fs::metadata(root.join("f")).unwrap();
+        let file = metadata.ino();
+        let generation = Inode::new(metadata, Contents::File).generation();
+        let mut backends = [
+            Filesystem::new_from_fs(root, scanner()).unwrap(),
+            Filesystem::new_from_sqlite("test", root, db, scanner()).unwrap(),
+        ];
+
+        // writes and chmod change ctime, but the file is the one opened all the same
+        File::options()
+            .append(true)
+            .open(root.join("f"))
+            .unwrap()
+            .write_all(b"more")
+            .unwrap();
+        std::fs::set_permissions(root.join("f"), std::fs::Permissions::from_mode(0o600)).unwrap();
+        for filesystem in &mut backends {
+            filesystem
+                .splice(scan_subtree(root, &scanner(), Path::new("f")).unwrap())
+                .unwrap();
+            let inode = filesystem.get_by_inode(file, generation).unwrap();
+            assert_eq!(inode.size, 4);
+            assert_eq!(inode.generation(), generation);
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let root = Path::new("/tmp/buhao-diff");
+        let db = Path::new("/tmp/buhao-diff.db");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        std::fs::create_dir_all(root.join("d/e")).unwrap();
+        File::create(root.join("d/e/same")).unwrap();
+        File::create(root.join("d/grows")).unwrap();
+        File::create(root.join("gone")).unwrap();
+        symlink("gone", root.join("link")).unwrap();
+        let old = Filesystem::new_from_sqlite("test", root, db, scanner()).unwrap();
+
+        File::create(root.join("d/e/new")).unwrap();
+        File::options()
+            .append(true)
+            .open(root.join("d/grows"))
+            .unwrap()
+            .write_all(b"more")
+            .unwrap();
+        std::fs::remove_file(root.join("gone")).unwrap();
+        std::fs::remove_file(root.join("link")).unwrap();
+        symlink("d", root.join("link")).unwrap();
+        let mut new = old.next_epoch().unwrap();
+        new.populate().unwrap();
+
+        let maintenance = new.maintenance().unwrap();
+        let (from, to) = (old.epoch(), new.epoch());
+        let changes = |after: &str, limit| {
+            let page = maintenance.diff(from, to, after, limit).unwrap();
+            let changes: Vec<_> = page
+                .changes
+                .into_iter()
+                .map(|changed| (changed.path, changed.change))
+                .collect();
+            (changes, page.next)
+        };
+        // in pages of 2
+        let mut all = Vec::new();
+        let mut after = String::new();
+        loop {
+            let (page, next) = changes(&after, 2);
+            assert!(page.len() <= 2);
+            all.extend(page);
+            match next {
+                Some(next) => after = next,
+                None => break,
+            }
+        }
+        // directories may or may not have a new mtime within the same second
+        all.retain(|(path, _)| path != "d" && path != "d/e");
+        assert_eq!(
+            all,
+            vec![
+                ("d/e/new".to_string(), Change::Added),
+                ("d/grows".to_string(), Change::Modified),
+                ("gone".to_string(), Change::Removed),
+                ("link".to_string(), Change::Modified),
+            ]
+        );
+
+        // a subtree refresh of the new epoch shows up in the diff
+        File::create(root.join("d/e/newer")).unwrap();
+        new.splice(scan_subtree(root, &scanner(), Path::new("d/e")).unwrap())
+            .unwrap();
+        let (rest, _) = changes("d/e/new", 100);
+        assert_eq!(rest[0], ("d/e/newer".to_string(), Change::Added));
+        assert!(maintenance.diff(from, 100, "", 10).is_err());
+    }
 }
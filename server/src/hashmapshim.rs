@@ -1,154 +1,1279 @@
-use std::{fmt::Debug, path::Path};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    ops::{Bound, Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 
-use buhao_lib::InodeId;
+use anyhow::{anyhow, Result};
+use buhao_lib::{
+    Change, ChangedPath, Contents, DiffPage, DirectoryContents, DirectoryItem, EpochStats, Inode,
+    InodeId, InodeType, StorageStats, INVALID_PARENT,
+};
+use log::warn;
+use rusqlite::OptionalExtension;
+
+/// Bump this when the layout of the tables changes, so that stale databases get rebuilt
+const SCHEMA_VERSION: i32 = 9;
+
+/// Rows deleted per statement when collecting old epochs, so that writers are never locked out for long
+const GC_CHUNK: usize = 10000;
+
+/// Idle read-only connections kept around for concurrent lookups
+const MAX_IDLE_READERS: usize = 16;
 
 /// A shim over std hashmap or sqlite3
-pub trait HashMapShim<K, V>: Send + Debug {
-    fn insert(&mut self, key: K, value: V);
-    fn get(&self, key: &K) -> Option<V>;
-    fn remove(&mut self, key: &K);
+pub trait HashMapShim<K, V>: Send + Sync + Debug {
+    fn insert(&mut self, key: K, value: V) -> Result<()>;
+    fn insert_many(&mut self, items: Vec<(K, V)>) -> Result<()> {
+        for (key, value) in items {
+            self.insert(key, value)?;
+        }
+        Ok(())
+    }
+    /// Group the writes until commit, so that they are applied at once.
+    /// Readers may not see any of them before commit. Writers go through Transaction,
+    /// which rolls back unless committed.
+    fn begin(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Throw away the writes since begin
+    fn rollback(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// The value of key, or None if there is none. Errors of the backend are never taken for None.
+    fn get(&self, key: &K) -> Result<Option<V>>;
+    fn remove(&mut self, key: &K) -> Result<()>;
     fn values(&self) -> Vec<V>;
+    /// The generation this map reads from and writes to
+    fn epoch(&self) -> u64;
+}
+
+/// Directory-aware operations over a map of inodes.
+/// The defaults work on whole inodes, backends that index directory entries could do better.
+pub trait InodeMap: HashMapShim<InodeId, Inode> {
+    /// Create an empty map for the next generation, leaving this one readable
+    fn next_epoch(&self) -> Result<Box<dyn InodeMap>>;
+
+    /// A map reading this generation, which stays readable after a newer one replaces this map
+    fn pin(&self) -> Result<Box<dyn InodeMap>>;
+
+    /// Find the entry called name in directory parent
+    fn lookup(&self, parent: InodeId, name: &str) -> Result<Option<DirectoryItem>> {
+        match self.get(&parent)?.map(|inode| inode.contents) {
+            Some(Contents::Directory(contents)) => {
+                Ok(contents.children.into_iter().find(|item| item.name == name))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Inode id with the entries of a directory left out, which backends could skip reading
+    fn get_shallow(&self, id: InodeId) -> Result<Option<Inode>> {
+        let mut inode = match self.get(&id)? {
+            Some(inode) => inode,
+            None => return Ok(None),
+        };
+        if let Contents::Directory(ref mut contents) = inode.contents {
+            contents.children = Vec::new();
+        }
+        Ok(Some(inode))
+    }
+
+    /// Up to limit entries of directory id with names after after, ordered by name.
+    /// Backends keep entries ordered, so that a page costs no more than its size.
+    fn readdir(&self, id: InodeId, after: &str, limit: usize) -> Result<Vec<DirectoryItem>>;
+
+    /// Entries linking to inode id, as their directory and name.
+    /// Directories have one, and files have one for each of their hard links.
+    fn links(&self, id: InodeId) -> Result<Vec<(InodeId, String)>>;
+
+    /// Parent of directory id
+    fn parent(&self, id: InodeId) -> Result<Option<InodeId>> {
+        match self.get(&id)?.map(|inode| inode.contents) {
+            Some(Contents::Directory(contents)) => Ok(Some(contents.parent)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Housekeeping of the generations kept, for backends that keep more than the current one
+    fn maintenance(&self) -> Option<Box<dyn Maintenance>> {
+        None
+    }
+}
+
+/// Writes to a map between begin and commit.
+/// Dropping it without committing rolls the writes back, so that an error halfway never leaves the map
+/// stuck in a transaction.
+pub struct Transaction<'a> {
+    map: &'a mut dyn InodeMap,
+    committed: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn begin(map: &'a mut dyn InodeMap) -> Result<Self> {
+        map.begin()?;
+        Ok(Self {
+            map,
+            committed: false,
+        })
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.map.commit()?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl<'a> Deref for Transaction<'a> {
+    type Target = dyn InodeMap + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        self.map
+    }
+}
+
+impl<'a> DerefMut for Transaction<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.map
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = self.map.rollback() {
+                warn!("Failed to roll back: {}", e);
+            }
+        }
+    }
+}
+
+/// Housekeeping on the generations of a root.
+/// It works apart from the map, so that no lock on the filesystem is held meanwhile.
+pub trait Maintenance: Send + Sync + Debug {
+    /// Delete every generation but `epoch`, the newest `keep - 1` complete ones before it and pinned ones,
+    /// and give their space back. Returns the deleted epochs.
+    /// Partial generations left by failed refreshes go as well, so it must not run while one is written.
+    fn collect_garbage(&self, epoch: u64, keep: usize, pinned: &[u64]) -> Result<Vec<u64>>;
+    fn stats(&self) -> Result<StorageStats>;
+    /// Paths that changed from epoch `old` to epoch `new`, ordered by path and starting after `after`
+    fn diff(&self, old: u64, new: u64, after: &str, limit: usize) -> Result<DiffPage>;
+}
+
+fn change_from_sql(change: u8) -> rusqlite::Result<Change> {
+    match change {
+        0 => Ok(Change::Added),
+        1 => Ok(Change::Removed),
+        2 => Ok(Change::Modified),
+        _ => Err(rusqlite::Error::IntegralValueOutOfRange(0, change as i64)),
+    }
+}
+
+/// Inodes of a generation in memory, with the entries linking to each of them
+#[derive(Debug, Default)]
+struct Generation {
+    inodes: std::collections::HashMap<InodeId, Inode>,
+    /// Entries of each directory by name, for lookups and pages of readdir
+    entries: std::collections::HashMap<InodeId, BTreeMap<String, DirectoryItem>>,
+    /// Reverse index of directory entries, from an inode to its directories and names
+    links: std::collections::HashMap<InodeId, Vec<(InodeId, String)>>,
+}
+
+impl Generation {
+    fn insert(&mut self, id: InodeId, inode: Inode) {
+        self.unlink(id);
+        if let Contents::Directory(ref contents) = inode.contents {
+            for item in &contents.children {
+                self.links
+                    .entry(item.inode)
+                    .or_default()
+                    .push((id, item.name.clone()));
+            }
+            let entries = contents
+                .children
+                .iter()
+                .map(|item| (item.name.clone(), item.clone()))
+                .collect();
+            self.entries.insert(id, entries);
+        }
+        self.inodes.insert(id, inode);
+    }
+
+    fn remove(&mut self, id: InodeId) {
+        self.unlink(id);
+        self.inodes.remove(&id);
+    }
+
+    /// Drop the entries of directory id from the indexes
+    fn unlink(&mut self, id: InodeId) {
+        self.entries.remove(&id);
+        let contents = match self.inodes.get(&id).map(|inode| &inode.contents) {
+            Some(Contents::Directory(contents)) => contents,
+            _ => return,
+        };
+        for item in &contents.children {
+            if let Some(links) = self.links.get_mut(&item.inode) {
+                links.retain(|(parent, name)| *parent != id || *name != item.name);
+                if links.is_empty() {
+                    self.links.remove(&item.inode);
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct StdHashMap<K, V> {
-    map: std::collections::HashMap<K, V>,
+/// Pins share the map of their generation, so that they see the same writes as with SQLite,
+/// and it is freed along with the last of them
+#[derive(Debug)]
+pub struct StdHashMap {
+    map: Arc<RwLock<Generation>>,
+    epoch: u64,
 }
 
-impl<K, V> HashMapShim<K, V> for StdHashMap<K, V>
-where
-    K: std::hash::Hash + Eq + Send + Debug + Clone,
-    V: Clone + Send + Debug,
-{
-    fn insert(&mut self, key: K, value: V) {
-        self.map.insert(key, value);
+impl HashMapShim<InodeId, Inode> for StdHashMap {
+    fn insert(&mut self, key: InodeId, value: Inode) -> Result<()> {
+        self.map.write().unwrap().insert(key, value);
+        Ok(())
     }
 
-    fn get(&self, key: &K) -> Option<V> {
-        self.map.get(key).cloned()
+    fn insert_many(&mut self, items: Vec<(InodeId, Inode)>) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        for (key, value) in items {
+            map.insert(key, value);
+        }
+        Ok(())
     }
 
-    fn remove(&mut self, key: &K) {
-        self.map.remove(key);
+    fn get(&self, key: &InodeId) -> Result<Option<Inode>> {
+        Ok(self.map.read().unwrap().inodes.get(key).cloned())
     }
 
-    fn values(&self) -> Vec<V> {
-        self.map.values().cloned().collect()
+    fn remove(&mut self, key: &InodeId) -> Result<()> {
+        self.map.write().unwrap().remove(*key);
+        Ok(())
+    }
+
+    fn values(&self) -> Vec<Inode> {
+        self.map.read().unwrap().inodes.values().cloned().collect()
+    }
+
+    fn epoch(&self) -> u64 {
+        self.epoch
     }
 }
 
-impl<K, V> StdHashMap<K, V>
-where
-    K: std::hash::Hash + Eq,
-{
+impl InodeMap for StdHashMap {
+    fn next_epoch(&self) -> Result<Box<dyn InodeMap>> {
+        Ok(Box::new(Self::new_epoch(self.epoch + 1)))
+    }
+
+    fn pin(&self) -> Result<Box<dyn InodeMap>> {
+        Ok(Box::new(Self {
+            map: self.map.clone(),
+            epoch: self.epoch,
+        }))
+    }
+
+    fn get_shallow(&self, id: InodeId) -> Result<Option<Inode>> {
+        let map = self.map.read().unwrap();
+        let Some(inode) = map.inodes.get(&id) else {
+            return Ok(None);
+        };
+        let contents = match inode.contents {
+            Contents::Directory(ref contents) => Contents::Directory(DirectoryContents {
+                parent: contents.parent,
+                children: Vec::new(),
+            }),
+            ref contents => contents.clone(),
+        };
+        Ok(Some(Inode { contents, ..*inode }))
+    }
+
+    fn links(&self, id: InodeId) -> Result<Vec<(InodeId, String)>> {
+        let map = self.map.read().unwrap();
+        Ok(map.links.get(&id).cloned().unwrap_or_default())
+    }
+
+    fn lookup(&self, parent: InodeId, name: &str) -> Result<Option<DirectoryItem>> {
+        let map = self.map.read().unwrap();
+        let entries = map.entries.get(&parent);
+        Ok(entries.and_then(|entries| entries.get(name)).cloned())
+    }
+
+    fn readdir(&self, id: InodeId, after: &str, limit: usize) -> Result<Vec<DirectoryItem>> {
+        let map = self.map.read().unwrap();
+        let Some(entries) = map.entries.get(&id) else {
+            return Ok(Vec::new());
+        };
+        let range = entries.range::<str, _>((Bound::Excluded(after), Bound::Unbounded));
+        Ok(range.take(limit).map(|(_, item)| item.clone()).collect())
+    }
+}
+
+impl StdHashMap {
     #[allow(dead_code)]
     pub fn new() -> Self {
+        Self::new_epoch(0)
+    }
+
+    fn new_epoch(epoch: u64) -> Self {
         Self {
-            map: std::collections::HashMap::new(),
+            map: Arc::new(RwLock::new(Generation::default())),
+            epoch,
         }
     }
 }
 
+fn kind_to_sql(itype: &InodeType) -> u8 {
+    match itype {
+        InodeType::File => 0,
+        InodeType::Directory => 1,
+        InodeType::Symlink => 2,
+    }
+}
+
+fn kind_from_sql(kind: u8) -> rusqlite::Result<InodeType> {
+    match kind {
+        0 => Ok(InodeType::File),
+        1 => Ok(InodeType::Directory),
+        2 => Ok(InodeType::Symlink),
+        _ => Err(rusqlite::Error::IntegralValueOutOfRange(0, kind as i64)),
+    }
+}
+
+/// Inodes keep their metadata in columns, and directory entries live in a table of their own,
+/// so that a path component is resolved by one indexed lookup on (epoch, parent, name).
+///
+/// Writes go through conn, while lookups take a read-only connection from the pool,
+/// so that they could run concurrently (with WAL, also alongside a writer on the next epoch).
 #[derive(Debug)]
-pub struct SqliteHashMap<K, V> {
-    pub conn: rusqlite::Connection,
+pub struct SqliteHashMap {
+    conn: Mutex<rusqlite::Connection>,
+    readers: Mutex<Vec<rusqlite::Connection>>,
+    path: PathBuf,
+    /// Name of the root this map belongs to, as roots share the database
+    root: String,
     epoch: u64,
-    key_type: std::marker::PhantomData<K>,
-    value_type: std::marker::PhantomData<V>,
+    /// Filter rules of the scans writing this map, kept with each complete epoch
+    filter: String,
 }
 
-impl<V> SqliteHashMap<InodeId, V> {
+impl SqliteHashMap {
     pub fn create(&self) -> rusqlite::Result<()> {
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS inodemap (
-                key INT PRIMARY KEY,
-                value TEXT,
-                epoch INT
-            )",
-            (),
-        )?;
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_epoch on inodemap(epoch)",
-            (),
-        )?;
-        Ok(())
+        self.conn.lock().unwrap().execute_batch(
+            "CREATE TABLE IF NOT EXISTS inodes (
+                root TEXT,
+                epoch INT,
+                id INT,
+                mode INT,
+                uid INT,
+                gid INT,
+                nlink INT,
+                atime INT,
+                mtime INT,
+                ctime INT,
+                -- birth time, NULL where the filesystem keeps none
+                btime INT,
+                btime_nsec INT,
+                size INT,
+                kind INT,
+                -- symlink target
+                target TEXT,
+                -- parent of a directory, NULL for the root
+                parent INT,
+                PRIMARY KEY (root, epoch, id)
+            );
+            CREATE TABLE IF NOT EXISTS dirents (
+                root TEXT,
+                epoch INT,
+                parent INT,
+                name TEXT,
+                inode INT,
+                kind INT,
+                PRIMARY KEY (root, epoch, parent, name)
+            );
+            -- reverse lookups, from an inode to the entries linking to it
+            CREATE INDEX IF NOT EXISTS dirents_inode ON dirents (root, epoch, inode);
+            -- computed diffs between epochs, kept for paging until either epoch changes
+            CREATE TABLE IF NOT EXISTS diffs (
+                root TEXT,
+                old INT,
+                new INT,
+                path TEXT,
+                -- added, removed or modified
+                change INT,
+                PRIMARY KEY (root, old, new, path)
+            );
+            -- filter rules each complete epoch was scanned with, written with its root inode
+            CREATE TABLE IF NOT EXISTS epochs (
+                root TEXT,
+                epoch INT,
+                filter TEXT,
+                PRIMARY KEY (root, epoch)
+            );
+            CREATE TABLE IF NOT EXISTS diffs_computed (
+                root TEXT,
+                old INT,
+                new INT,
+                -- 0 while it is computed, until its rows are in diffs
+                complete INT,
+                PRIMARY KEY (root, old, new)
+            );",
+        )
     }
 
     pub fn drop_(&self) -> rusqlite::Result<()> {
-        self.conn.execute("DROP TABLE IF EXISTS inodemap", ())?;
-        Ok(())
+        self.conn.lock().unwrap().execute_batch(
+            "DROP TABLE IF EXISTS inodemap;
+            DROP TABLE IF EXISTS inodes;
+            DROP TABLE IF EXISTS dirents;
+            DROP TABLE IF EXISTS diffs;
+            DROP TABLE IF EXISTS epochs;
+            DROP TABLE IF EXISTS diffs_computed;",
+        )
     }
 
-    pub fn new(path: &Path) -> rusqlite::Result<Self> {
+    pub fn new(path: &Path, root: &str) -> rusqlite::Result<Self> {
         let conn = rusqlite::Connection::open(path)?;
+        // a refresh writes the next epoch through another connection while this one keeps reading
+        conn.busy_timeout(Duration::from_secs(30))?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        // with WAL, this only syncs on checkpoints, and a crash could at worst lose the last commits
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        let version: i32 = conn.query_row("PRAGMA user_version", (), |row| row.get(0))?;
         let obj = Self {
-            conn,
-            key_type: std::marker::PhantomData,
-            value_type: std::marker::PhantomData,
+            conn: Mutex::new(conn),
+            readers: Mutex::new(Vec::new()),
+            path: path.to_path_buf(),
+            root: root.to_string(),
             epoch: 0,
+            filter: String::new(),
         };
+        if version != SCHEMA_VERSION {
+            obj.drop_()?;
+            let conn = obj.conn.lock().unwrap();
+            // pages of deleted epochs are given back with incremental_vacuum,
+            // and a VACUUM is needed for that to take effect on an existing database
+            conn.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+            conn.execute_batch("VACUUM")?;
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
         obj.create()?;
         Ok(obj)
     }
 
-    pub fn init_by_root(&mut self, inode: u64) {
-        let root_epoch = self
+    /// Run f with a read-only connection from the pool
+    fn read<T>(
+        &self,
+        f: impl FnOnce(&rusqlite::Connection) -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
+        let conn = self.readers.lock().unwrap().pop();
+        let conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let conn = rusqlite::Connection::open_with_flags(
+                    &self.path,
+                    rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+                        | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )?;
+                conn.busy_timeout(Duration::from_secs(30))?;
+                conn
+            }
+        };
+        let result = f(&conn);
+        let mut readers = self.readers.lock().unwrap();
+        if readers.len() < MAX_IDLE_READERS {
+            readers.push(conn);
+        }
+        result
+    }
+
+    /// Switch to the newest epoch that has the root inode, which is only written once a scan completes.
+    /// Returns false if there is none.
+    pub fn init_by_root(&mut self, inode: u64) -> rusqlite::Result<bool> {
+        let root_epoch: Option<u64> = self.conn.get_mut().unwrap().query_row(
+            "SELECT MAX(epoch) FROM inodes WHERE root = ?1 AND id = ?2",
+            rusqlite::params![self.root, inode],
+            |row| row.get(0),
+        )?;
+        self.epoch = root_epoch.unwrap_or(0);
+        Ok(root_epoch.is_some())
+    }
+
+    /// Set the filter rules that scans into this map use
+    pub fn with_filter(mut self, filter: String) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Whether the current epoch was scanned with the filter rules of this map
+    pub fn filter_matches(&mut self) -> rusqlite::Result<bool> {
+        let filter: Option<String> = self
             .conn
+            .get_mut()
+            .unwrap()
             .query_row(
-                "SELECT MAX(epoch) FROM inodemap WHERE key = ?1",
-                rusqlite::params![inode],
+                "SELECT filter FROM epochs WHERE root = ?1 AND epoch = ?2",
+                rusqlite::params![self.root, self.epoch],
                 |row| row.get(0),
             )
-            .unwrap_or(0);
-        self.epoch = root_epoch;
+            .optional()?;
+        Ok(filter.as_ref() == Some(&self.filter))
+    }
+
+    /// Remove every epoch of this root, leaving other roots alone
+    pub fn clear(&self) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM inodes WHERE root = ?1",
+            rusqlite::params![self.root],
+        )?;
+        conn.execute(
+            "DELETE FROM dirents WHERE root = ?1",
+            rusqlite::params![self.root],
+        )?;
+        conn.execute(
+            "DELETE FROM epochs WHERE root = ?1",
+            rusqlite::params![self.root],
+        )?;
+        Ok(())
+    }
+
+    /// Read inode id, with the entries of a directory only if entries is set
+    fn get_inode(
+        &self,
+        conn: &rusqlite::Connection,
+        id: InodeId,
+        entries: bool,
+    ) -> rusqlite::Result<Inode> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT mode, uid, gid, nlink, atime, mtime, ctime, btime, btime_nsec, size, kind, target, parent
+            FROM inodes WHERE root = ?1 AND epoch = ?2 AND id = ?3",
+        )?;
+        let (mut inode, target, parent) =
+            stmt.query_row(rusqlite::params![self.root, self.epoch, id], |row| {
+                let kind = kind_from_sql(row.get(10)?)?;
+                let inode = Inode {
+                    id,
+                    mode: row.get(0)?,
+                    uid: row.get(1)?,
+                    gid: row.get(2)?,
+                    nlink: row.get(3)?,
+                    atime: row.get(4)?,
+                    mtime: row.get(5)?,
+                    ctime: row.get(6)?,
+                    btime: row
+                        .get::<_, Option<i64>>(7)?
+                        .zip(row.get::<_, Option<i64>>(8)?),
+                    size: row.get(9)?,
+                    contents: match kind {
+                        InodeType::File => Contents::File,
+                        InodeType::Symlink => Contents::Symlink(String::new()),
+                        InodeType::Directory => Contents::Directory(DirectoryContents {
+                            parent: 0,
+                            children: Vec::new(),
+                        }),
+                    },
+                };
+                Ok((
+                    inode,
+                    row.get::<_, Option<String>>(11)?,
+                    row.get::<_, Option<InodeId>>(12)?,
+                ))
+            })?;
+        match inode.contents {
+            Contents::Symlink(ref mut link) => *link = target.unwrap_or_default(),
+            Contents::Directory(ref mut contents) => {
+                contents.parent = parent.unwrap_or(INVALID_PARENT);
+                if !entries {
+                    return Ok(inode);
+                }
+                let mut stmt = conn.prepare_cached(
+                    "SELECT name, inode, kind FROM dirents
+                    WHERE root = ?1 AND epoch = ?2 AND parent = ?3 ORDER BY rowid",
+                )?;
+                let rows = stmt.query_map(rusqlite::params![self.root, self.epoch, id], |row| {
+                    Ok(DirectoryItem {
+                        name: row.get(0)?,
+                        inode: row.get(1)?,
+                        itype: kind_from_sql(row.get(2)?)?,
+                    })
+                })?;
+                contents.children = rows.collect::<rusqlite::Result<_>>()?;
+            }
+            Contents::File => {}
+        }
+        Ok(inode)
     }
 }
 
-impl<V> HashMapShim<InodeId, V> for SqliteHashMap<InodeId, V>
-where
-    V: serde::Serialize + serde::de::DeserializeOwned + Send + Debug + Clone,
-{
-    fn insert(&mut self, key: InodeId, value: V) {
-        let value = serde_json::to_string(&value).unwrap();
+impl HashMapShim<InodeId, Inode> for SqliteHashMap {
+    fn insert(&mut self, key: InodeId, value: Inode) -> Result<()> {
+        self.insert_many(vec![(key, value)])
+    }
+
+    fn insert_many(&mut self, items: Vec<(InodeId, Inode)>) -> Result<()> {
+        let epoch = self.epoch;
+        let root = &self.root;
+        let conn = self.conn.get_mut().unwrap();
+        let mut insert_inode = conn.prepare_cached(
+            "INSERT OR REPLACE INTO inodes
+            (root, epoch, id, mode, uid, gid, nlink, atime, mtime, ctime, btime, btime_nsec, size, kind, target, parent)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+        )?;
+        let mut delete_dirents = conn
+            .prepare_cached("DELETE FROM dirents WHERE root = ?1 AND epoch = ?2 AND parent = ?3")?;
+        let mut insert_dirent = conn.prepare_cached(
+            "INSERT OR REPLACE INTO dirents (root, epoch, parent, name, inode, kind)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        let mut insert_epoch = conn.prepare_cached(
+            "INSERT OR REPLACE INTO epochs (root, epoch, filter) VALUES (?1, ?2, ?3)",
+        )?;
+        for (key, value) in items {
+            let (target, parent) = match value.contents {
+                Contents::File => (None, None),
+                Contents::Symlink(ref target) => (Some(target), None),
+                Contents::Directory(ref contents) if contents.parent == INVALID_PARENT => {
+                    insert_epoch.execute(rusqlite::params![root, epoch, self.filter])?;
+                    (None, None)
+                }
+                Contents::Directory(ref contents) => (None, Some(contents.parent)),
+            };
+            insert_inode.execute(rusqlite::params![
+                root,
+                epoch,
+                key,
+                value.mode,
+                value.uid,
+                value.gid,
+                value.nlink,
+                value.atime,
+                value.mtime,
+                value.ctime,
+                value.btime.map(|btime| btime.0),
+                value.btime.map(|btime| btime.1),
+                value.size,
+                kind_to_sql(&value.itype()),
+                target,
+                parent
+            ])?;
+            delete_dirents.execute(rusqlite::params![root, epoch, key])?;
+            if let Contents::Directory(ref contents) = value.contents {
+                for item in &contents.children {
+                    insert_dirent.execute(rusqlite::params![
+                        root,
+                        epoch,
+                        key,
+                        item.name,
+                        item.inode,
+                        kind_to_sql(&item.itype)
+                    ])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        // take the write lock now, instead of failing halfway if another writer holds it
         self.conn
-            .execute(
-                "INSERT OR REPLACE INTO inodemap (key, value, epoch) VALUES (?1, ?2, ?3)",
-                rusqlite::params![key, value, self.epoch],
-            )
-            .unwrap();
+            .get_mut()
+            .unwrap()
+            .execute_batch("BEGIN IMMEDIATE")?;
+        Ok(())
     }
 
-    fn get(&self, key: &InodeId) -> Option<V> {
-        let value: Option<String> = self
-            .conn
-            .query_row(
-                "SELECT value FROM inodemap WHERE key = ?1 AND epoch = ?2",
-                rusqlite::params![key, self.epoch],
+    fn commit(&mut self) -> Result<()> {
+        let conn = self.conn.get_mut().unwrap();
+        // diffs against this epoch are out of date, and get computed again when asked
+        conn.prepare_cached(
+            "DELETE FROM diffs_computed WHERE root = ?1 AND (old = ?2 OR new = ?2)",
+        )?
+        .execute(rusqlite::params![self.root, self.epoch])?;
+        conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        let conn = self.conn.get_mut().unwrap();
+        // some errors roll the transaction back by themselves
+        if !conn.is_autocommit() {
+            conn.execute_batch("ROLLBACK")?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &InodeId) -> Result<Option<Inode>> {
+        Ok(self.read(|conn| self.get_inode(conn, *key, true).optional())?)
+    }
+
+    fn remove(&mut self, key: &InodeId) -> Result<()> {
+        let params = rusqlite::params![self.root, self.epoch, key];
+        let conn = self.conn.get_mut().unwrap();
+        conn.execute(
+            "DELETE FROM inodes WHERE root = ?1 AND epoch = ?2 AND id = ?3",
+            params,
+        )?;
+        conn.execute(
+            "DELETE FROM dirents WHERE root = ?1 AND epoch = ?2 AND parent = ?3",
+            params,
+        )?;
+        Ok(())
+    }
+
+    fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn values(&self) -> Vec<Inode> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare("SELECT id FROM inodes WHERE root = ?1 AND epoch = ?2")?;
+            let ids = stmt
+                .query_map(rusqlite::params![self.root, self.epoch], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<InodeId>>>()?;
+            ids.into_iter()
+                .map(|id| self.get_inode(conn, id, true))
+                .collect()
+        })
+        .unwrap()
+    }
+}
+
+impl InodeMap for SqliteHashMap {
+    fn next_epoch(&self) -> Result<Box<dyn InodeMap>> {
+        let mut next = Self::new(&self.path, &self.root)?;
+        // an interrupted refresh may have left rows behind a higher epoch, so skip past all of them
+        let max_epoch: Option<u64> = self.read(|conn| {
+            conn.query_row(
+                "SELECT MAX(epoch) FROM inodes WHERE root = ?1",
+                rusqlite::params![self.root],
                 |row| row.get(0),
             )
-            .ok();
-        value.and_then(|value| serde_json::from_str(&value).ok())
+        })?;
+        next.epoch = max_epoch.unwrap_or(0).max(self.epoch) + 1;
+        next.filter = self.filter.clone();
+        Ok(Box::new(next))
     }
 
-    fn remove(&mut self, key: &InodeId) {
-        self.conn
-            .execute(
-                "DELETE FROM inodemap WHERE key = ?1 AND epoch = ?2",
-                rusqlite::params![key, self.epoch],
+    fn pin(&self) -> Result<Box<dyn InodeMap>> {
+        let mut pinned = Self::new(&self.path, &self.root)?;
+        pinned.epoch = self.epoch;
+        pinned.filter = self.filter.clone();
+        Ok(Box::new(pinned))
+    }
+
+    fn maintenance(&self) -> Option<Box<dyn Maintenance>> {
+        Some(Box::new(SqliteMaintenance {
+            path: self.path.clone(),
+            root: self.root.clone(),
+        }))
+    }
+
+    fn lookup(&self, parent: InodeId, name: &str) -> Result<Option<DirectoryItem>> {
+        let item = self.read(|conn| {
+            conn.prepare_cached(
+                "SELECT inode, kind FROM dirents
+                WHERE root = ?1 AND epoch = ?2 AND parent = ?3 AND name = ?4",
+            )?
+            .query_row(
+                rusqlite::params![self.root, self.epoch, parent, name],
+                |row| {
+                    Ok(DirectoryItem {
+                        name: name.to_string(),
+                        inode: row.get(0)?,
+                        itype: kind_from_sql(row.get(1)?)?,
+                    })
+                },
             )
-            .unwrap();
+            .optional()
+        })?;
+        Ok(item)
     }
 
-    fn values(&self) -> Vec<V> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT value FROM inodemap WHERE epoch = ?1")
+    fn get_shallow(&self, id: InodeId) -> Result<Option<Inode>> {
+        Ok(self.read(|conn| self.get_inode(conn, id, false).optional())?)
+    }
+
+    fn links(&self, id: InodeId) -> Result<Vec<(InodeId, String)>> {
+        let links = self.read(|conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT parent, name FROM dirents WHERE root = ?1 AND epoch = ?2 AND inode = ?3",
+            )?;
+            let rows = stmt.query_map(rusqlite::params![self.root, self.epoch, id], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+            rows.collect()
+        })?;
+        Ok(links)
+    }
+
+    fn readdir(&self, id: InodeId, after: &str, limit: usize) -> Result<Vec<DirectoryItem>> {
+        let entries = self.read(|conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT name, inode, kind FROM dirents
+                WHERE root = ?1 AND epoch = ?2 AND parent = ?3 AND name > ?4
+                ORDER BY name LIMIT ?5",
+            )?;
+            let rows = stmt.query_map(
+                rusqlite::params![self.root, self.epoch, id, after, limit],
+                |row| {
+                    Ok(DirectoryItem {
+                        name: row.get(0)?,
+                        inode: row.get(1)?,
+                        itype: kind_from_sql(row.get(2)?)?,
+                    })
+                },
+            )?;
+            rows.collect()
+        })?;
+        Ok(entries)
+    }
+
+    fn parent(&self, id: InodeId) -> Result<Option<InodeId>> {
+        let row = self.read(|conn| {
+            conn.prepare_cached(
+                "SELECT kind, parent FROM inodes WHERE root = ?1 AND epoch = ?2 AND id = ?3",
+            )?
+            .query_row(rusqlite::params![self.root, self.epoch, id], |row| {
+                Ok((
+                    kind_from_sql(row.get(0)?)?,
+                    row.get::<_, Option<InodeId>>(1)?,
+                ))
+            })
+            .optional()
+        })?;
+        match row {
+            Some((InodeType::Directory, parent)) => Ok(Some(parent.unwrap_or(INVALID_PARENT))),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SqliteMaintenance {
+    path: PathBuf,
+    root: String,
+}
+
+impl SqliteMaintenance {
+    fn connect(&self) -> rusqlite::Result<rusqlite::Connection> {
+        let conn = rusqlite::Connection::open(&self.path)?;
+        conn.busy_timeout(Duration::from_secs(30))?;
+        Ok(conn)
+    }
+
+    /// Fill the temporary table with path and metadata of every entry of epoch
+    fn list_epoch(&self, conn: &rusqlite::Connection, table: &str, epoch: u64) -> Result<()> {
+        let found: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM inodes
+            WHERE root = ?1 AND epoch = ?2 AND kind = 1 AND parent IS NULL)",
+            rusqlite::params![self.root, epoch],
+            |row| row.get(0),
+        )?;
+        if !found {
+            return Err(anyhow!("Epoch {} of {} is not available", epoch, self.root));
+        }
+        conn.execute_batch(&format!(
+            "CREATE TEMP TABLE IF NOT EXISTS {0} (
+                path TEXT PRIMARY KEY, mode INT, size INT, mtime INT, target TEXT
+            );
+            DELETE FROM {0};",
+            table
+        ))?;
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (path, mode, size, mtime, target)
+                WITH RECURSIVE tree(path, inode, kind) AS (
+                    SELECT d.name, d.inode, d.kind FROM inodes r JOIN dirents d
+                    ON d.root = r.root AND d.epoch = r.epoch AND d.parent = r.id
+                    WHERE r.root = ?1 AND r.epoch = ?2 AND r.kind = 1 AND r.parent IS NULL
+                    UNION ALL
+                    SELECT tree.path || '/' || d.name, d.inode, d.kind FROM tree JOIN dirents d
+                    ON d.root = ?1 AND d.epoch = ?2 AND d.parent = tree.inode
+                    WHERE tree.kind = 1
+                )
+                SELECT tree.path, i.mode, i.size, i.mtime, i.target FROM tree JOIN inodes i
+                ON i.root = ?1 AND i.epoch = ?2 AND i.id = tree.inode",
+                table
+            ),
+            rusqlite::params![self.root, epoch],
+        )?;
+        Ok(())
+    }
+
+    /// Rows of this root in table, by epoch
+    fn count(
+        &self,
+        conn: &rusqlite::Connection,
+        table: &str,
+    ) -> rusqlite::Result<std::collections::BTreeMap<u64, u64>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT epoch, COUNT(*) FROM {} WHERE root = ?1 GROUP BY epoch",
+            table
+        ))?;
+        let rows = stmt.query_map(rusqlite::params![self.root], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        rows.collect()
+    }
+}
+
+impl Maintenance for SqliteMaintenance {
+    fn collect_garbage(&self, epoch: u64, keep: usize, pinned: &[u64]) -> Result<Vec<u64>> {
+        let conn = self.connect()?;
+        // an epoch is complete once it has its root inode, which populate writes last
+        let kept = conn
+            .prepare(
+                "SELECT DISTINCT epoch FROM inodes
+                WHERE root = ?1 AND epoch < ?2 AND kind = 1 AND parent IS NULL
+                ORDER BY epoch DESC LIMIT ?3",
+            )?
+            .query_map(
+                rusqlite::params![self.root, epoch, keep.saturating_sub(1)],
+                |row| row.get(0),
+            )?
+            .collect::<rusqlite::Result<Vec<u64>>>()?;
+        let epochs = conn
+            .prepare(
+                "SELECT epoch FROM inodes WHERE root = ?1
+                UNION SELECT epoch FROM dirents WHERE root = ?1
+                ORDER BY epoch",
+            )?
+            .query_map(rusqlite::params![self.root], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<u64>>>()?;
+        let stale: Vec<u64> = epochs
+            .into_iter()
+            .filter(|stale| *stale != epoch && !kept.contains(stale) && !pinned.contains(stale))
+            .collect();
+        for epoch in &stale {
+            // entries first, so that an interrupted run leaves no entries without their inodes
+            conn.execute(
+                "DELETE FROM diffs_computed WHERE root = ?1 AND (old = ?2 OR new = ?2)",
+                rusqlite::params![self.root, epoch],
+            )?;
+            conn.execute(
+                "DELETE FROM diffs WHERE root = ?1 AND (old = ?2 OR new = ?2)",
+                rusqlite::params![self.root, epoch],
+            )?;
+            conn.execute(
+                "DELETE FROM epochs WHERE root = ?1 AND epoch = ?2",
+                rusqlite::params![self.root, epoch],
+            )?;
+            for table in ["dirents", "inodes"] {
+                let sql = format!(
+                    "DELETE FROM {0} WHERE rowid IN
+                    (SELECT rowid FROM {0} WHERE root = ?1 AND epoch = ?2 LIMIT {1})",
+                    table, GC_CHUNK
+                );
+                while conn.execute(&sql, rusqlite::params![self.root, epoch])? > 0 {}
+            }
+        }
+        if !stale.is_empty() {
+            conn.execute_batch("PRAGMA incremental_vacuum")?;
+        }
+        Ok(stale)
+    }
+
+    fn stats(&self) -> Result<StorageStats> {
+        let conn = self.connect()?;
+        let pragma = |name: &str| conn.query_row(&format!("PRAGMA {}", name), (), |row| row.get(0));
+        let page_size: u64 = pragma("page_size")?;
+        let size = pragma("page_count")? * page_size;
+        let free = pragma("freelist_count")? * page_size;
+        let rows: u64 = conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM inodes) + (SELECT COUNT(*) FROM dirents)",
+            (),
+            |row| row.get(0),
+        )?;
+
+        let inodes = self.count(&conn, "inodes")?;
+        let dirents = self.count(&conn, "dirents")?;
+        let epochs = inodes
+            .into_iter()
+            .map(|(epoch, inodes)| {
+                let dirents = dirents.get(&epoch).copied().unwrap_or(0);
+                EpochStats {
+                    epoch,
+                    inodes,
+                    dirents,
+                    bytes: (size - free) * (inodes + dirents) / rows.max(1),
+                }
+            })
+            .collect();
+        Ok(StorageStats { epochs, size, free })
+    }
+
+    fn diff(&self, old: u64, new: u64, after: &str, limit: usize) -> Result<DiffPage> {
+        let mut conn = self.connect()?;
+        let params = rusqlite::params![self.root, old, new];
+        let computed: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM diffs_computed
+            WHERE root = ?1 AND old = ?2 AND new = ?3 AND complete = 1)",
+            params,
+            |row| row.get(0),
+        )?;
+        let source = if computed {
+            "(SELECT path, change FROM diffs WHERE root = ?1 AND old = ?2 AND new = ?3)"
+        } else {
+            // a commit to either epoch deletes the marker, and with it the rows computed before
+            conn.execute(
+                "INSERT OR IGNORE INTO diffs_computed (root, old, new, complete)
+                VALUES (?1, ?2, ?3, 0)",
+                params,
+            )?;
+            // the listings only read the epochs, so writers are not held up while they run
+            let tx = conn.transaction()?;
+            self.list_epoch(&tx, "diff_old", old)?;
+            self.list_epoch(&tx, "diff_new", new)?;
+            tx.execute_batch(
+                "CREATE TEMP TABLE IF NOT EXISTS diff_changes (path TEXT PRIMARY KEY, change INT);
+                DELETE FROM diff_changes;
+                INSERT INTO diff_changes (path, change)
+                SELECT path, 0 FROM diff_new
+                WHERE path NOT IN (SELECT path FROM diff_old)
+                UNION ALL
+                SELECT path, 1 FROM diff_old
+                WHERE path NOT IN (SELECT path FROM diff_new)
+                UNION ALL
+                SELECT n.path, 2 FROM diff_new n JOIN diff_old o ON n.path = o.path
+                WHERE n.mode IS NOT o.mode OR n.size IS NOT o.size
+                OR n.mtime IS NOT o.mtime OR n.target IS NOT o.target;",
+            )?;
+            tx.commit()?;
+            // kept for the next pages, unless the marker is gone and the rows may be out of date
+            let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+            let marked = tx.execute(
+                "UPDATE diffs_computed SET complete = 1
+                WHERE root = ?1 AND old = ?2 AND new = ?3 AND complete = 0",
+                params,
+            )?;
+            if marked > 0 {
+                tx.execute(
+                    "DELETE FROM diffs WHERE root = ?1 AND old = ?2 AND new = ?3",
+                    params,
+                )?;
+                tx.execute(
+                    "INSERT INTO diffs (root, old, new, path, change)
+                    SELECT ?1, ?2, ?3, path, change FROM diff_changes",
+                    params,
+                )?;
+            }
+            tx.commit()?;
+            "diff_changes"
+        };
+        let changes = conn
+            .prepare(&format!(
+                "SELECT path, change FROM {} WHERE path > ?4 ORDER BY path LIMIT ?5",
+                source
+            ))?
+            .query_map(
+                rusqlite::params![self.root, old, new, after, limit],
+                |row| {
+                    Ok(ChangedPath {
+                        path: row.get(0)?,
+                        change: change_from_sql(row.get(1)?)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let next = match changes.last() {
+            Some(last) if changes.len() == limit => Some(last.path.clone()),
+            _ => None,
+        };
+        Ok(DiffPage { changes, next })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    fn inode(id: InodeId, contents: Contents) -> Inode {
+        Inode {
+            id,
+            mode: 0o644,
+            uid: 1000,
+            gid: 1000,
+            nlink: 1,
+            atime: 1,
+            mtime: 2,
+            ctime: 3,
+            btime: Some((3, 0)),
+            size: 4,
+            contents,
+        }
+    }
+
+    #[test]
+    fn test_sqlite_layout() {
+        let db = Path::new("/tmp/buhao-layout.db");
+        std::fs::remove_file(db).unwrap_or(());
+        let mut map = SqliteHashMap::new(db, "test").unwrap();
+        let root = inode(
+            1,
+            Contents::Directory(DirectoryContents {
+                parent: INVALID_PARENT,
+                children: vec![
+                    DirectoryItem {
+                        name: "z".to_string(),
+                        inode: 2,
+                        itype: InodeType::Symlink,
+                    },
+                    DirectoryItem {
+                        name: "a".to_string(),
+                        inode: 3,
+                        itype: InodeType::File,
+                    },
+                ],
+            }),
+        );
+        map.insert(1, root.clone()).unwrap();
+        map.insert(2, inode(2, Contents::Symlink("a".to_string())))
+            .unwrap();
+        map.insert(3, inode(3, Contents::File)).unwrap();
+
+        // directory entries keep their order
+        assert_eq!(
+            format!("{:?}", map.get(&1).unwrap()),
+            format!("{:?}", Some(root))
+        );
+        assert!(
+            matches!(map.get(&2).unwrap().unwrap().contents, Contents::Symlink(ref target) if target == "a")
+        );
+        assert_eq!(map.lookup(1, "a").unwrap().unwrap().inode, 3);
+        assert!(map.lookup(1, "b").unwrap().is_none());
+        assert_eq!(map.parent(1).unwrap(), Some(INVALID_PARENT));
+        assert_eq!(map.parent(3).unwrap(), None);
+        // without entries, but the rest of the directory
+        match map.get_shallow(1).unwrap().unwrap().contents {
+            Contents::Directory(contents) => {
+                assert_eq!(contents.parent, INVALID_PARENT);
+                assert!(contents.children.is_empty());
+            }
+            _ => panic!("Not a directory"),
+        }
+        assert!(
+            matches!(map.get_shallow(2).unwrap().unwrap().contents, Contents::Symlink(ref target) if target == "a")
+        );
+
+        map.remove(&1).unwrap();
+        assert!(map.get(&1).unwrap().is_none());
+        assert!(map.lookup(1, "a").unwrap().is_none());
+
+        // a failing query is an error, never taken for a missing entry
+        map.conn
+            .get_mut()
+            .unwrap()
+            .execute_batch("DROP TABLE dirents")
+            .unwrap();
+        assert!(map.lookup(1, "a").is_err());
+        assert!(map.get(&2).is_ok());
+        map.conn
+            .get_mut()
+            .unwrap()
+            .execute_batch("DROP TABLE inodes")
             .unwrap();
-        let rows = stmt
-            .query_map(rusqlite::params![self.epoch], |row| row.get(0))
+        assert!(map.get_shallow(2).is_err());
+        assert!(map.parent(1).is_err());
+    }
+
+    #[test]
+    fn test_std_pin() {
+        let mut map = StdHashMap::new();
+        map.insert(1, inode(1, Contents::File)).unwrap();
+        let pinned = map.pin().unwrap();
+        // the generation is shared rather than copied
+        map.insert(2, inode(2, Contents::File)).unwrap();
+        assert!(pinned.get(&2).unwrap().is_some());
+        let mut next = map.next_epoch().unwrap();
+        next.insert(3, inode(3, Contents::File)).unwrap();
+        drop(map);
+        assert_eq!(pinned.values().len(), 2);
+        assert!(pinned.get(&3).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sqlite_batch() {
+        let db = Path::new("/tmp/buhao-batch.db");
+        std::fs::remove_file(db).unwrap_or(());
+        let mut map = SqliteHashMap::new(db, "test").unwrap();
+
+        map.begin().unwrap();
+        map.insert_many(
+            (1..=1000)
+                .map(|id| (id, inode(id, Contents::File)))
+                .collect(),
+        )
+        .unwrap();
+        // readers do not see the batch until it is committed
+        assert!(map.get(&1).unwrap().is_none());
+        map.commit().unwrap();
+        assert_eq!(map.values().len(), 1000);
+        assert!(map.get(&1000).unwrap().is_some());
+
+        // a transaction dropped halfway is rolled back, and the next one starts cleanly
+        {
+            let mut transaction = Transaction::begin(&mut map).unwrap();
+            transaction.remove(&1).unwrap();
+        }
+        assert!(map.get(&1).unwrap().is_some());
+        let mut transaction = Transaction::begin(&mut map).unwrap();
+        transaction.remove(&1).unwrap();
+        transaction.commit().unwrap();
+        assert!(map.get(&1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let db = Path::new("/tmp/buhao-gc.db");
+        std::fs::remove_file(db).unwrap_or(());
+        let mut other = SqliteHashMap::new(db, "other").unwrap();
+        other.insert(1, inode(1, Contents::File)).unwrap();
+        let mut map = SqliteHashMap::new(db, "test").unwrap();
+        // epochs 3 and 5 have no root inode, like the ones left by failed refreshes
+        for epoch in 1..=5 {
+            map.epoch = epoch;
+            map.begin().unwrap();
+            map.insert_many(
+                (1..=1000)
+                    .map(|id| (id, inode(id, Contents::File)))
+                    .collect(),
+            )
             .unwrap();
-        rows.map(|row| row.unwrap())
-            .map(|value: String| serde_json::from_str(&value).unwrap())
-            .collect()
+            if epoch != 3 && epoch != 5 {
+                let root = Contents::Directory(DirectoryContents {
+                    parent: INVALID_PARENT,
+                    children: Vec::new(),
+                });
+                map.insert(1001, inode(1001, root)).unwrap();
+            }
+            map.commit().unwrap();
+        }
+        map.epoch = 4;
+
+        let maintenance = map.maintenance().unwrap();
+        let before = maintenance.stats().unwrap();
+        assert_eq!(before.epochs.len(), 5);
+        assert_eq!(before.epochs[0].inodes, 1001);
+        // the current epoch and the complete one before it are kept, and so is a pinned one,
+        // while partial epochs go even above the current one
+        assert_eq!(maintenance.collect_garbage(4, 2, &[1]).unwrap(), vec![3, 5]);
+        assert_eq!(maintenance.collect_garbage(4, 2, &[]).unwrap(), vec![1]);
+        let after = maintenance.stats().unwrap();
+        let epochs: Vec<_> = after.epochs.iter().map(|stats| stats.epoch).collect();
+        assert_eq!(epochs, vec![2, 4]);
+        assert!(after.size < before.size);
+        assert!(map.get(&1000).unwrap().is_some());
+        assert!(other.get(&1).unwrap().is_some());
+        assert!(maintenance.collect_garbage(4, 2, &[]).unwrap().is_empty());
     }
 }
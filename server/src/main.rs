@@ -1,90 +1,474 @@
+use anyhow::{anyhow, Context};
 use buhao_lib::{
-    convert_response_tuple, BuhaoCodec, RequestActionType, ResponseActionType, BUHAO_SOCK_PATH,
+    BuhaoError, DiffRequest, Encoding, ErrorKind, GetByInodeRequest, GetManyRequest, GetRequest,
+    Hello, Inode, PinRequest, ReaddirRequest, RefreshRequest, Request, RequestActionType,
+    RequestId, Response, ServerCodec, StatsRequest,
 };
-use serde_json::json;
 use std::{
-    path::Path,
+    collections::{BTreeMap, HashMap},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use tokio_util::codec::Framed;
 
-use futures::sink::SinkExt;
+use futures::{sink::SinkExt, stream::StreamExt};
 use log::{debug, warn};
-use tokio::net::UnixListener;
-use tokio_stream::StreamExt;
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, Semaphore},
+};
+
+/// Requests of a connection handled at the same time, before the server stops reading more
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Paths in a page of diff, if the client does not ask for a size
+const DIFF_PAGE_SIZE: usize = 1000;
+const MAX_DIFF_PAGE_SIZE: usize = 100000;
+
+/// Entries in a page of readdir, if the client does not ask for a size
+const READDIR_PAGE_SIZE: usize = 1000;
+const MAX_READDIR_PAGE_SIZE: usize = 100000;
+
+mod config;
+use config::{Backend, Config};
+
+mod filter;
+use filter::Filter;
 
 mod fs;
 use fs::Filesystem;
 
 mod hashmapshim;
 
+mod root;
+use root::{Pin, Root, Roots};
+
+mod scanner;
+use scanner::Scanner;
+
+mod watcher;
+
+/// Pinned epochs of a connection by root name, shared by its requests in flight.
+/// They are released when the connection closes.
+type Pins = Mutex<HashMap<String, Arc<Pin>>>;
+
+fn no_such_root(name: &str) -> anyhow::Error {
+    BuhaoError::new(ErrorKind::InvalidRequest, format!("No such root: {}", name)).into()
+}
+
+fn unmanaged(path: &Path) -> BuhaoError {
+    BuhaoError::new(
+        ErrorKind::Unmanaged,
+        format!("Unmanaged path: {}", path.display()),
+    )
+}
+
+/// The root named, or every root if none is
+fn select(roots: &Roots, name: Option<&str>) -> anyhow::Result<Vec<Arc<Root>>> {
+    match name {
+        Some(name) => match roots.get(name) {
+            Some(root) => Ok(vec![root]),
+            None => Err(no_such_root(name)),
+        },
+        None => Ok(roots.iter().cloned().collect()),
+    }
+}
+
+/// Refresh the root named in request, the root managing its path, or every root if neither is given.
+/// Returns the new epoch of each refreshed root. A root failing does not keep the others from
+/// being refreshed, and the error then lists each failed root along with the refreshed ones.
+async fn refresh(roots: &Roots, request: RefreshRequest) -> anyhow::Result<Response> {
+    let targets = match (request.root, request.path) {
+        (Some(name), path) => match roots.get(&name) {
+            Some(root) => vec![(root, path)],
+            None => return Err(no_such_root(&name)),
+        },
+        (None, Some(path)) => match roots.route(&path) {
+            Some((root, path)) => vec![(root, Some(path))],
+            None => return Err(unmanaged(&path).into()),
+        },
+        (None, None) => roots.iter().map(|root| (root.clone(), None)).collect(),
+    };
+    let mut epochs = BTreeMap::new();
+    let mut failed = Vec::new();
+    for (root, path) in targets {
+        match root.refresh(path).await {
+            Ok(epoch) => {
+                epochs.insert(root.name.clone(), epoch);
+            }
+            Err(e) => failed.push((root.name.clone(), e)),
+        }
+    }
+    match failed.len() {
+        0 => Ok(Response::Epochs(epochs)),
+        // a single root keeps the kind of its error
+        1 if epochs.is_empty() => Err(failed.pop().unwrap().1),
+        _ => {
+            let failed: Vec<_> = failed
+                .iter()
+                .map(|(name, e)| format!("{}: {:#}", name, e))
+                .collect();
+            Err(anyhow!(
+                "Failed to refresh {}; refreshed {:?}",
+                failed.join("; "),
+                epochs
+            ))
+        }
+    }
+}
+
+/// Look up the path of request, in the epoch pinned for its root if any
+async fn open(roots: &Roots, request: GetRequest, pins: &Pins) -> anyhow::Result<Inode> {
+    let (root, path) = roots
+        .route(&request.path)
+        .ok_or_else(|| unmanaged(&request.path))?;
+    let pin = pins.lock().unwrap().get(&root.name).cloned();
+    match pin {
+        Some(pin) => pin.open(path, request.follow).await,
+        None => root.open(path, request.follow).await,
+    }
+}
+
+async fn get(roots: &Roots, request: GetRequest, pins: &Pins) -> anyhow::Result<Response> {
+    Ok(Response::Inode(open(roots, request, pins).await?))
+}
+
+/// Attributes of the inode at the path of request, without its contents
+async fn lookup(roots: &Roots, request: GetRequest, pins: &Pins) -> anyhow::Result<Response> {
+    let inode = open(roots, request, pins).await?;
+    Ok(Response::Attributes(inode.attributes()))
+}
+
+/// The inode with the id of request in its root, in the epoch pinned for the root if any
+async fn get_by_inode(
+    roots: &Roots,
+    request: GetByInodeRequest,
+    pins: &Pins,
+) -> anyhow::Result<Response> {
+    let root = roots
+        .get(&request.root)
+        .ok_or_else(|| no_such_root(&request.root))?;
+    let pin = pins.lock().unwrap().get(&root.name).cloned();
+    let inode = match pin {
+        Some(pin) => pin.get_by_inode(request.id, request.generation).await?,
+        None => root.get_by_inode(request.id, request.generation).await?,
+    };
+    Ok(Response::Inode(inode))
+}
+
+/// Every path of the inode with the id of request, one for each hard link
+async fn paths(roots: &Roots, request: GetByInodeRequest, pins: &Pins) -> anyhow::Result<Response> {
+    let root = roots
+        .get(&request.root)
+        .ok_or_else(|| no_such_root(&request.root))?;
+    let pin = pins.lock().unwrap().get(&root.name).cloned();
+    let paths = match pin {
+        Some(pin) => pin.paths(request.id, request.generation).await?,
+        None => root.paths(request.id, request.generation).await?,
+    };
+    Ok(Response::Paths(paths))
+}
+
+/// Look up every path of request in the order given, each one failing on its own.
+/// Paths of the same root are looked up together, in one epoch.
+async fn get_many(roots: &Roots, request: GetManyRequest, pins: &Pins) -> anyhow::Result<Response> {
+    let mut results = Vec::with_capacity(request.paths.len());
+    // each root with its paths, and where their results go
+    let mut batches: Vec<(Arc<Root>, Vec<usize>, Vec<PathBuf>)> = Vec::new();
+    for (i, path) in request.paths.iter().enumerate() {
+        let Some((root, path)) = roots.route(path) else {
+            results.push(Err(unmanaged(path)));
+            continue;
+        };
+        // filled in below
+        results.push(Ok(None));
+        match batches
+            .iter_mut()
+            .find(|(other, ..)| Arc::ptr_eq(other, &root))
+        {
+            Some((_, indices, paths)) => {
+                indices.push(i);
+                paths.push(path);
+            }
+            None => batches.push((root, vec![i], vec![path])),
+        }
+    }
+    for (root, indices, paths) in batches {
+        let pin = pins.lock().unwrap().get(&root.name).cloned();
+        let inodes = match pin {
+            Some(pin) => pin.open_many(paths, request.follow).await?,
+            None => root.open_many(paths, request.follow).await?,
+        };
+        for (i, inode) in indices.into_iter().zip(inodes) {
+            results[i] = inode.map(Some);
+        }
+    }
+    let inodes = results
+        .into_iter()
+        .map(|result| result.map(|inode| inode.expect("every managed path is looked up")))
+        .collect();
+    Ok(Response::Inodes(inodes))
+}
+
+/// A page of the entries of the directory at the path of request
+async fn readdir(roots: &Roots, request: ReaddirRequest, pins: &Pins) -> anyhow::Result<Response> {
+    let (root, path) = roots
+        .route(&request.path)
+        .ok_or_else(|| unmanaged(&request.path))?;
+    let limit = request.limit.map_or(READDIR_PAGE_SIZE, |limit| {
+        limit.clamp(1, MAX_READDIR_PAGE_SIZE)
+    });
+    let pin = pins.lock().unwrap().get(&root.name).cloned();
+    let page = match pin {
+        Some(pin) => pin.readdir(path, request.after, limit).await?,
+        None => root.readdir(path, request.after, limit).await?,
+    };
+    Ok(Response::Directory(page))
+}
+
+/// Epochs and their sizes of the root named in request, or of every root
+async fn stats(roots: &Roots, request: StatsRequest) -> anyhow::Result<Response> {
+    let mut stats = Vec::new();
+    for root in select(roots, request.root.as_deref())? {
+        stats.push(root.stats().await?);
+    }
+    Ok(Response::Stats(stats))
+}
+
+/// Pin the root named in request, or every root, to its current epoch for the rest of the connection.
+/// Pinning again moves on to the current epoch. Returns the pinned epoch of each root.
+async fn pin(roots: &Roots, request: PinRequest, pins: &Pins) -> anyhow::Result<Response> {
+    let mut epochs = BTreeMap::new();
+    for root in select(roots, request.root.as_deref())? {
+        let pin = root.pin().await?;
+        epochs.insert(root.name.clone(), pin.epoch());
+        pins.lock()
+            .unwrap()
+            .insert(root.name.clone(), Arc::new(pin));
+    }
+    Ok(Response::Epochs(epochs))
+}
+
+/// Changes of root between epochs "from" and "to" (the current one if absent),
+/// in pages of "limit" paths starting after the "after" cursor
+async fn diff(roots: &Roots, request: DiffRequest) -> anyhow::Result<Response> {
+    let root = roots
+        .get(&request.root)
+        .ok_or_else(|| no_such_root(&request.root))?;
+    let limit = request
+        .limit
+        .map_or(DIFF_PAGE_SIZE, |limit| limit.clamp(1, MAX_DIFF_PAGE_SIZE));
+    let page = root
+        .diff(request.from, request.to, request.after, limit)
+        .await?;
+    Ok(Response::Diff(page))
+}
+
+/// Answer the Hello a connection starts with, taking the first encoding of the client that is known
+fn hello(roots: &Roots, client: &Hello) -> Result<Hello, BuhaoError> {
+    let encoding = client
+        .encodings
+        .iter()
+        .find_map(|encoding| Encoding::from_name(encoding))
+        .unwrap_or_default();
+    let hello = Hello::new(
+        &RequestActionType::ALL,
+        &[encoding],
+        roots.iter().map(|root| root.info()).collect(),
+    );
+    hello.accepts(client)?;
+    Ok(hello)
+}
+
+/// Answer a request, turning whatever went wrong into an error response
+async fn handle(roots: &Roots, request: Request, pins: &Pins) -> Response {
+    debug!("Request: {:?}", request);
+    let result = match request {
+        Request::Refresh(request) => refresh(roots, request).await,
+        Request::Get(request) => get(roots, request, pins).await,
+        Request::GetMany(request) => get_many(roots, request, pins).await,
+        Request::Readdir(request) => readdir(roots, request, pins).await,
+        Request::Lookup(request) => lookup(roots, request, pins).await,
+        Request::GetByInode(request) => get_by_inode(roots, request, pins).await,
+        Request::Paths(request) => paths(roots, request, pins).await,
+        Request::Roots => Ok(Response::Roots(
+            roots.iter().map(|root| root.info()).collect(),
+        )),
+        Request::Stats(request) => stats(roots, request).await,
+        Request::Pin(request) => pin(roots, request, pins).await,
+        Request::Diff(request) => diff(roots, request).await,
+        Request::Hello(_) => Err(BuhaoError::new(
+            ErrorKind::InvalidRequest,
+            "Hello is only the first request of a connection",
+        )
+        .into()),
+    };
+    result.unwrap_or_else(|e| Response::Error(BuhaoError::from_anyhow(&e)))
+}
+
+/// Serve the requests of a connection, which starts with Hello, until it is closed.
+/// Requests after Hello are handled concurrently, and each response goes out as soon as it is ready.
+async fn serve(roots: Arc<Roots>, socket: UnixStream) {
+    let mut framed = Framed::new(socket, ServerCodec::new());
+    let response = match framed.next().await {
+        Some(Ok((id, Ok(Request::Hello(client))))) => match hello(&roots, &client) {
+            Ok(hello) => Ok((id, hello)),
+            Err(error) => {
+                warn!("Rejected client: {}", error);
+                Err((id, error))
+            }
+        },
+        Some(Ok((id, _))) => Err((
+            id,
+            BuhaoError::new(
+                ErrorKind::Incompatible,
+                "Expected Hello as the first request",
+            ),
+        )),
+        Some(Err(e)) => {
+            warn!("Error decoding message: {}", e);
+            return;
+        }
+        None => return,
+    };
+    match response {
+        Ok((id, hello)) => {
+            // the answer itself is still framed and encoded like any Hello
+            let encoding = hello.encoding().unwrap_or_default();
+            if let Err(e) = framed.send((id, Response::Hello(hello))).await {
+                warn!("Error sending message: {}", e);
+                return;
+            }
+            framed.codec_mut().finish_hello(encoding);
+        }
+        // peers that did not agree on the protocol are not served
+        Err((id, error)) => {
+            framed.send((id, Response::Error(error))).await.ok();
+            return;
+        }
+    }
+
+    let (mut sink, mut stream) = framed.split();
+    // responses wait for a slow client here, so the queue is bounded like the requests in flight
+    let (sender, mut receiver) = mpsc::channel::<(RequestId, Response)>(MAX_IN_FLIGHT);
+    let writer = tokio::spawn(async move {
+        while let Some(response) = receiver.recv().await {
+            if let Err(e) = sink.send(response).await {
+                warn!("Error sending message: {}", e);
+                break;
+            }
+        }
+    });
+    let pins: Arc<Pins> = Arc::default();
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    while let Some(message) = stream.next().await {
+        match message {
+            Ok((id, Ok(request))) => {
+                // stop reading until a request is done, if too many are in flight already
+                let Ok(permit) = in_flight.clone().acquire_owned().await else {
+                    break;
+                };
+                let (roots, pins, sender) = (roots.clone(), pins.clone(), sender.clone());
+                tokio::spawn(async move {
+                    let response = handle(&roots, request, &pins).await;
+                    // the request counts as in flight until its response is queued
+                    sender.send((id, response)).await.ok();
+                    drop(permit);
+                });
+            }
+            // the frame was fine but its payload was not, so the client is told
+            Ok((id, Err(error))) => {
+                warn!("Invalid request: {}", error);
+                sender.send((id, Response::Error(error))).await.ok();
+            }
+            Err(e) => {
+                warn!("Error decoding message: {}", e);
+                break;
+            }
+        }
+    }
+    // the writer is done once every request in flight is answered
+    drop(sender);
+    writer.await.ok();
+}
+
+/// Path of the config file given with `--config <path>`, if any
+fn config_path() -> anyhow::Result<Option<PathBuf>> {
+    let mut args = std::env::args().skip(1);
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => match args.next() {
+                Some(value) => path = Some(PathBuf::from(value)),
+                None => return Err(anyhow!("--config requires a path")),
+            },
+            _ => match arg.strip_prefix("--config=") {
+                Some(value) => path = Some(PathBuf::from(value)),
+                None => return Err(anyhow!("Unknown argument: {}", arg)),
+            },
+        }
+    }
+    Ok(path)
+}
+
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
     // init logger
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let config = match config_path()? {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
+    };
     // unlink before bind if possible
-    std::fs::remove_file(BUHAO_SOCK_PATH).unwrap_or(());
-    let listener = UnixListener::bind(BUHAO_SOCK_PATH).unwrap();
-    // let filesystem = Arc::new(Mutex::new(Filesystem::new_from_fs(Path::new(
-    //     "/tmp/buhao/",
-    // ))));
-    let filesystem = Arc::new(Mutex::new(Filesystem::new_from_sqlite(
-        Path::new("/tmp/buhao/"),
-        Path::new("/tmp/buhao.db"),
-    )));
+    let sock_path = &config.socket.path;
+    std::fs::remove_file(sock_path).unwrap_or(());
+    let listener = UnixListener::bind(sock_path)
+        .with_context(|| format!("Failed to bind {}", sock_path.display()))?;
+    if let Some(mode) = config.socket.mode {
+        std::fs::set_permissions(sock_path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions of {}", sock_path.display()))?;
+    }
+    let mut roots = Roots::default();
+    for root in &config.roots {
+        // already checked when loading config
+        let filter = Filter::parse(&root.filter)?;
+        let scanner = Scanner::new(filter, root.scan_threads.unwrap_or(0))?;
+        let filesystem = match config.backend {
+            Backend::StdHashMap => Filesystem::new_from_fs(&root.path, scanner),
+            Backend::SqliteHashMap => {
+                Filesystem::new_from_sqlite(&root.name, &root.path, &config.database, scanner)
+            }
+        }
+        .with_context(|| format!("Failed to load root {}", root.name))?;
+        roots.add(
+            Root::new(
+                &root.name,
+                filesystem,
+                root.refresh.max_watches,
+                root.refresh.keep_epochs,
+                root.authoritative,
+            )
+            .with_context(|| format!("Failed to watch root {}", root.name))?,
+        )?;
+    }
+    let roots = Arc::new(roots);
+    // epochs left behind by an earlier run
+    for root in roots.iter() {
+        root.spawn_collect_garbage();
+    }
+    for root in &config.roots {
+        if let (Some(interval), Some(managed)) = (root.refresh.interval, roots.get(&root.name)) {
+            managed.spawn_periodic_refresh(Duration::from_secs(interval));
+        }
+    }
 
     loop {
         match listener.accept().await {
             Ok((socket, _addr)) => {
-                let filesystem = filesystem.clone();
-                tokio::spawn(async move {
-                    let mut framed = Framed::new(socket, BuhaoCodec);
-
-                    while let Some(message) = framed.next().await {
-                        match message {
-                            Ok((message_type, payload)) => {
-                                let action_type = RequestActionType::try_from(message_type);
-                                match action_type {
-                                    Ok(RequestActionType::Refresh) => {
-                                        unimplemented!("filesystem refresh")
-                                    }
-                                    Ok(RequestActionType::Get) => {
-                                        debug!("Get request: {}", payload);
-                                        let path = payload["path"].as_str().unwrap();
-                                        let path = Path::new(path);
-                                        let result = {
-                                            let filesystem = filesystem.lock().unwrap();
-                                            filesystem.open(path)
-                                        };
-                                        let result = match result {
-                                            Err(e) => {
-                                                (ResponseActionType::Error, json!(format!("{}", e)))
-                                            }
-                                            Ok(inode) => match inode.serialize_metadata() {
-                                                Err(e) => (
-                                                    ResponseActionType::Error,
-                                                    json!(format!("{}", e)),
-                                                ),
-                                                Ok(metadata) => (ResponseActionType::Ok, metadata),
-                                            },
-                                        };
-                                        if let Err(e) =
-                                            framed.send(convert_response_tuple(result)).await
-                                        {
-                                            warn!("Error sending message: {}", e);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        warn!("Error decoding message: {}", e);
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                warn!("Error decoding message: {}", e);
-                            }
-                        }
-                    }
-                });
+                let roots = roots.clone();
+                tokio::spawn(serve(roots, socket));
             }
             Err(e) => {
                 warn!("Error accepting connection: {}", e);
@@ -92,3 +476,357 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, os::unix::fs::MetadataExt, time::Instant};
+
+    use super::*;
+    use buhao_lib::{BuhaoCodec, ClientCodec, InodeType, ResponseActionType, PROTOCOL_VERSION};
+    use log::info;
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_hello() {
+        let roots = Roots::default();
+        let mut client = Hello::new(&[RequestActionType::Get], &[Encoding::Json], Vec::new());
+        client.encodings.insert(0, "cbor".to_string());
+        let server = hello(&roots, &client).unwrap();
+        assert_eq!(server.version, PROTOCOL_VERSION);
+        assert_eq!(server.encodings, vec!["json"]);
+        assert!(server.requests.iter().any(|request| request == "get"));
+        assert!(server.accepts(&client).is_ok());
+
+        let kind = |client: &Hello| hello(&roots, client).unwrap_err().kind;
+        let other = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..client.clone()
+        };
+        assert_eq!(kind(&other), ErrorKind::Incompatible);
+        let newer = Hello {
+            requests: vec!["get".to_string(), "teleport".to_string()],
+            ..client.clone()
+        };
+        assert_eq!(kind(&newer), ErrorKind::Incompatible);
+        assert!(hello(&roots, &newer)
+            .unwrap_err()
+            .message
+            .ends_with("teleport"));
+        let cbor = Hello {
+            encodings: vec!["cbor".to_string()],
+            ..client
+        };
+        assert_eq!(kind(&cbor), ErrorKind::Incompatible);
+    }
+
+    /// A version 1 client frames Hello without a request id, and is told that it does not fit
+    #[test(tokio::test)]
+    async fn test_v1_hello() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        tokio::spawn(serve(Arc::new(Roots::default()), server));
+        let hello = Hello {
+            version: 1,
+            requests: vec!["get".to_string()],
+            encodings: vec!["json".to_string()],
+            roots: Vec::new(),
+        };
+        let payload = serde_json::to_vec(&hello).unwrap();
+        let mut frame = b"buhao".to_vec();
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.push(u8::from(RequestActionType::Hello));
+        frame.extend_from_slice(&payload);
+        client.write_all(&frame).await.unwrap();
+
+        // the server closes the connection after its answer
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response[0..5], *b"buhao");
+        let len = u32::from_be_bytes(response[5..9].try_into().unwrap());
+        assert_eq!(len as usize, response.len() - 10);
+        assert_eq!(response[9], u8::from(ResponseActionType::Error));
+        let error: BuhaoError = serde_json::from_slice(&response[10..]).unwrap();
+        assert_eq!(error.kind, ErrorKind::Incompatible);
+    }
+
+    /// Not a strict benchmark, but the latency of the same Readdir in each encoding is logged
+    #[test(tokio::test)]
+    async fn test_encoding_latency() {
+        const ENTRIES: usize = 2000;
+        const ROUNDS: u32 = 50;
+        // a page of a large directory is where encoding costs the most
+        let path = Path::new("/tmp/buhao-encoding");
+        std::fs::remove_dir_all(path).unwrap_or(());
+        std::fs::create_dir_all(path).unwrap();
+        for i in 0..ENTRIES {
+            File::create(path.join(format!("file-{}", i))).unwrap();
+        }
+        let scanner = Scanner::new(Filter::default(), 0).unwrap();
+        let filesystem = Filesystem::new_from_fs(path, scanner).unwrap();
+        let mut roots = Roots::default();
+        roots
+            .add(Root::new("encoding", filesystem, None, 1, false).unwrap())
+            .unwrap();
+        let roots = Arc::new(roots);
+
+        let mut sizes = Vec::new();
+        for encoding in Encoding::ALL {
+            let (server, client) = UnixStream::pair().unwrap();
+            tokio::spawn(serve(roots.clone(), server));
+            let mut framed = Framed::new(client, ClientCodec::new());
+            let hello = Hello::new(&[RequestActionType::Readdir], &[encoding], Vec::new());
+            framed.send((0, Request::Hello(hello))).await.unwrap();
+            match framed.next().await {
+                Some(Ok((0, Ok(Response::Hello(server))))) => {
+                    assert_eq!(server.encoding(), Some(encoding))
+                }
+                response => panic!("Unexpected response to hello: {:?}", response),
+            }
+            framed.codec_mut().finish_hello(encoding);
+
+            let request = Request::Readdir(ReaddirRequest {
+                path: path.to_path_buf(),
+                after: String::new(),
+                limit: Some(ENTRIES),
+            });
+            let start = Instant::now();
+            let mut page = None;
+            for id in 1..=ROUNDS {
+                framed.send((id, request.clone())).await.unwrap();
+                match framed.next().await {
+                    Some(Ok((got_id, Ok(Response::Directory(got))))) if got_id == id => {
+                        page = Some(got)
+                    }
+                    response => panic!("Unexpected response to readdir: {:?}", response),
+                }
+            }
+            let latency = start.elapsed() / ROUNDS;
+            let page = page.unwrap();
+            assert_eq!(page.entries.len(), ENTRIES);
+            assert!(page.next.is_none());
+            let size = BuhaoCodec::new(encoding)
+                .encode_payload(&page)
+                .unwrap()
+                .len();
+            info!(
+                "{}: {:?} per request, {} bytes per response",
+                encoding.name(),
+                latency,
+                size
+            );
+            sizes.push((encoding, size));
+        }
+        let size = |encoding| sizes.iter().find(|(e, _)| *e == encoding).unwrap().1;
+        assert!(size(Encoding::MessagePack) < size(Encoding::Json));
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_many() {
+        let path = Path::new("/tmp/buhao-get-many");
+        std::fs::remove_dir_all(path).unwrap_or(());
+        std::fs::create_dir_all(path.join("d")).unwrap();
+        File::create(path.join("f")).unwrap();
+        std::os::unix::fs::symlink("f", path.join("l")).unwrap();
+        let scanner = Scanner::new(Filter::default(), 0).unwrap();
+        let filesystem = Filesystem::new_from_fs(path, scanner).unwrap();
+        let mut roots = Roots::default();
+        roots
+            .add(Root::new("getmany", filesystem, None, 1, false).unwrap())
+            .unwrap();
+        let roots = Arc::new(roots);
+
+        let ino = |name: &str| std::fs::symlink_metadata(path.join(name)).unwrap().ino();
+        let request = |follow| {
+            Request::GetMany(GetManyRequest {
+                paths: vec![
+                    path.join("l"),
+                    path.join("missing"),
+                    PathBuf::from("/elsewhere"),
+                    path.join("f/x"),
+                    PathBuf::from("getmany/d"),
+                ],
+                follow,
+            })
+        };
+        let Response::Inodes(inodes) = handle(&roots, request(false), &Pins::default()).await
+        else {
+            panic!("Expected inodes")
+        };
+        assert_eq!(inodes.len(), 5);
+        assert_eq!(inodes[0].as_ref().unwrap().id, ino("l"));
+        let kind = |i: usize| inodes[i].as_ref().unwrap_err().kind;
+        assert_eq!(kind(1), ErrorKind::NotFound);
+        assert_eq!(kind(2), ErrorKind::Unmanaged);
+        assert_eq!(kind(3), ErrorKind::NotADirectory);
+        assert_eq!(inodes[4].as_ref().unwrap().id, ino("d"));
+
+        let Response::Inodes(inodes) = handle(&roots, request(true), &Pins::default()).await else {
+            panic!("Expected inodes")
+        };
+        assert_eq!(inodes[0].as_ref().unwrap().id, ino("f"));
+
+        let lookup = |path: &str, follow| {
+            Request::Lookup(GetRequest {
+                path: PathBuf::from(path),
+                follow,
+            })
+        };
+        match handle(&roots, lookup("getmany/l", false), &Pins::default()).await {
+            Response::Attributes(attributes) => {
+                assert_eq!(attributes.id, ino("l"));
+                assert!(matches!(attributes.itype, InodeType::Symlink));
+            }
+            response => panic!("Unexpected response: {:?}", response),
+        }
+        match handle(&roots, lookup("getmany/d", true), &Pins::default()).await {
+            Response::Attributes(attributes) => {
+                assert!(matches!(attributes.itype, InodeType::Directory))
+            }
+            response => panic!("Unexpected response: {:?}", response),
+        }
+    }
+
+    /// Excluded entries may exist on disk, so that even an authoritative root leaves them to the real filesystem
+    #[test(tokio::test)]
+    async fn test_refresh_all() {
+        let base = Path::new("/tmp/buhao-refresh-all");
+        std::fs::remove_dir_all(base).unwrap_or(());
+        let mut roots = Roots::default();
+        for name in ["a", "b", "c"] {
+            std::fs::create_dir_all(base.join(name)).unwrap();
+            let scanner = Scanner::new(Filter::default(), 0).unwrap();
+            let filesystem = Filesystem::new_from_fs(&base.join(name), scanner).unwrap();
+            roots
+                .add(Root::new(name, filesystem, None, 1, false).unwrap())
+                .unwrap();
+        }
+        let roots = Arc::new(roots);
+        std::fs::remove_dir_all(base.join("b")).unwrap();
+        File::create(base.join("c/new")).unwrap();
+
+        let request = Request::Refresh(RefreshRequest {
+            root: None,
+            path: None,
+        });
+        match handle(&roots, request, &Pins::default()).await {
+            Response::Error(error) => {
+                assert!(error.message.starts_with("Failed to refresh b: "));
+                assert!(error.message.contains(r#""a""#));
+                assert!(error.message.contains(r#""c""#));
+            }
+            response => panic!("Unexpected response: {:?}", response),
+        }
+        // the other roots are refreshed all the same
+        let request = Request::Get(GetRequest {
+            path: base.join("c/new"),
+            follow: false,
+        });
+        assert!(matches!(
+            handle(&roots, request, &Pins::default()).await,
+            Response::Inode(_)
+        ));
+    }
+
+    #[test(tokio::test)]
+    async fn test_excluded() {
+        let path = Path::new("/tmp/buhao-excluded");
+        std::fs::remove_dir_all(path).unwrap_or(());
+        std::fs::create_dir_all(path.join(".~tmp~")).unwrap();
+        File::create(path.join(".~tmp~/partial")).unwrap();
+        File::create(path.join("db.lock")).unwrap();
+        let filter = Filter::parse(&["- .~tmp~/", "- *.lock"]).unwrap();
+        let filesystem = Filesystem::new_from_fs(path, Scanner::new(filter, 0).unwrap()).unwrap();
+        let mut roots = Roots::default();
+        roots
+            .add(Root::new("excluded", filesystem, None, 1, true).unwrap())
+            .unwrap();
+        let roots = Arc::new(roots);
+
+        let lookup = |name: &str| {
+            Request::Lookup(GetRequest {
+                path: path.join(name),
+                follow: true,
+            })
+        };
+        for name in ["db.lock", ".~tmp~/partial"] {
+            match handle(&roots, lookup(name), &Pins::default()).await {
+                Response::Error(error) => {
+                    assert_eq!(error.kind, ErrorKind::Unmanaged);
+                    assert_eq!(error.errno, 0);
+                }
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        }
+        match handle(&roots, lookup("missing"), &Pins::default()).await {
+            Response::Error(error) => assert_eq!(error.kind, ErrorKind::NotFound),
+            response => panic!("Unexpected response: {:?}", response),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_pipelining() {
+        let path = Path::new("/tmp/buhao-pipelining");
+        std::fs::remove_dir_all(path).unwrap_or(());
+        std::fs::create_dir_all(path).unwrap();
+        for i in 0..100 {
+            File::create(path.join(format!("{}", i))).unwrap();
+        }
+        let scanner = Scanner::new(Filter::default(), 0).unwrap();
+        let filesystem = Filesystem::new_from_fs(path, scanner).unwrap();
+        let mut roots = Roots::default();
+        roots
+            .add(Root::new("pipelining", filesystem, None, 1, false).unwrap())
+            .unwrap();
+        let roots = Arc::new(roots);
+
+        let (server, client) = UnixStream::pair().unwrap();
+        tokio::spawn(serve(roots, server));
+        let mut framed = Framed::new(client, ClientCodec::new());
+        let hello = Hello::new(&RequestActionType::ALL, &[Encoding::Json], Vec::new());
+        framed.send((0, Request::Hello(hello))).await.unwrap();
+        assert!(matches!(
+            framed.next().await,
+            Some(Ok((0, Ok(Response::Hello(_)))))
+        ));
+        framed.codec_mut().finish_hello(Encoding::Json);
+
+        // a slow refresh, and lookups that do not wait for it nor for each other
+        framed
+            .send((1000, Request::Refresh(RefreshRequest::default())))
+            .await
+            .unwrap();
+        // ids past 100 are of missing files
+        for id in 0..120 {
+            let request = Request::Get(GetRequest {
+                path: path.join(format!("{}", id)),
+                follow: false,
+            });
+            framed.send((id, request)).await.unwrap();
+        }
+        let mut answered = Vec::new();
+        for _ in 0..121 {
+            let (id, response) = framed.next().await.unwrap().unwrap();
+            match response.unwrap() {
+                Response::Epochs(epochs) => {
+                    assert_eq!(id, 1000);
+                    assert_eq!(epochs["pipelining"], 1);
+                }
+                Response::Inode(inode) => {
+                    assert!(id < 100);
+                    let expected = std::fs::metadata(path.join(format!("{}", id))).unwrap();
+                    assert_eq!(inode.id, expected.ino());
+                }
+                Response::Error(error) => {
+                    assert!(id >= 100);
+                    assert_eq!(error.kind, ErrorKind::NotFound);
+                }
+                response => panic!("Unexpected response: {:?}", response),
+            }
+            answered.push(id);
+        }
+        answered.sort();
+        let mut expected: Vec<_> = (0..120).collect();
+        expected.push(1000);
+        assert_eq!(answered, expected);
+    }
+}
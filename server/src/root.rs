@@ -0,0 +1,510 @@
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use buhao_lib::{BuhaoError, DiffPage, DirectoryPage, Inode, InodeId, RootInfo, RootStats};
+use log::{info, warn};
+
+use crate::fs::{scan_subtree, Filesystem};
+use crate::watcher::Watcher;
+
+/// A separately managed tree (like a rsync module), with its own epoch and refresh lifecycle
+#[derive(Debug)]
+pub struct Root {
+    pub name: String,
+    pub path: PathBuf,
+    filesystem: Arc<RwLock<Filesystem>>,
+    refreshing: AtomicBool,
+    watcher: Option<Watcher>,
+    keep_epochs: usize,
+    authoritative: bool,
+    /// Number of pins on each epoch, which are kept from garbage collection
+    pins: Arc<Mutex<BTreeMap<u64, usize>>>,
+    /// Held while a full refresh writes the next epoch and while garbage is collected,
+    /// so that the collection never takes an epoch still being written for a partial one
+    writing: Arc<Mutex<()>>,
+}
+
+/// Lookups pinned to the epoch that was current when the pin was taken, across full refreshes.
+/// The epoch is kept from garbage collection until the pin is dropped.
+/// Subtree refreshes still apply while it is the current epoch.
+#[derive(Debug)]
+pub struct Pin {
+    root: Arc<Root>,
+    epoch: u64,
+    filesystem: Arc<Filesystem>,
+}
+
+impl Pin {
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub async fn open(&self, path: PathBuf, follow: bool) -> Result<Inode> {
+        let filesystem = self.filesystem.clone();
+        tokio::task::spawn_blocking(move || filesystem.stat(&path, follow)).await?
+    }
+
+    pub async fn get_by_inode(&self, id: InodeId, generation: Option<(i64, i64)>) -> Result<Inode> {
+        let filesystem = self.filesystem.clone();
+        tokio::task::spawn_blocking(move || filesystem.get_by_inode(id, generation)).await?
+    }
+
+    pub async fn paths(&self, id: InodeId, generation: Option<(i64, i64)>) -> Result<Vec<String>> {
+        let filesystem = self.filesystem.clone();
+        tokio::task::spawn_blocking(move || filesystem.paths(id, generation)).await?
+    }
+
+    pub async fn readdir(
+        &self,
+        path: PathBuf,
+        after: String,
+        limit: usize,
+    ) -> Result<DirectoryPage> {
+        let filesystem = self.filesystem.clone();
+        tokio::task::spawn_blocking(move || filesystem.readdir(&path, &after, limit)).await?
+    }
+
+    pub async fn open_many(
+        &self,
+        paths: Vec<PathBuf>,
+        follow: bool,
+    ) -> Result<Vec<Result<Inode, BuhaoError>>> {
+        let filesystem = self.filesystem.clone();
+        Ok(tokio::task::spawn_blocking(move || resolve_many(&filesystem, &paths, follow)).await?)
+    }
+}
+
+/// Look up paths one by one, keeping what went wrong with each
+fn resolve_many(
+    filesystem: &Filesystem,
+    paths: &[PathBuf],
+    follow: bool,
+) -> Vec<Result<Inode, BuhaoError>> {
+    paths
+        .iter()
+        .map(|path| {
+            filesystem
+                .stat(path, follow)
+                .map_err(|e| BuhaoError::from_anyhow(&e))
+        })
+        .collect()
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        // the epoch itself goes with the next garbage collection
+        let mut pins = self.root.pins.lock().unwrap();
+        if let Some(count) = pins.get_mut(&self.epoch) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.epoch);
+            }
+        }
+    }
+}
+
+impl Root {
+    /// Start serving filesystem. inotify watching is enabled by giving a limit of watches.
+    /// Full refreshes leave keep_epochs epochs in storage.
+    /// Clients are told to trust negative lookups if authoritative is set.
+    pub fn new(
+        name: &str,
+        filesystem: Filesystem,
+        max_watches: Option<usize>,
+        keep_epochs: usize,
+        authoritative: bool,
+    ) -> Result<Self> {
+        let path = filesystem.root_path().to_path_buf();
+        let filesystem = Arc::new(RwLock::new(filesystem));
+        let watcher = match max_watches {
+            Some(max_watches) => Some(Watcher::spawn(filesystem.clone(), max_watches)?),
+            None => None,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            path,
+            filesystem,
+            refreshing: AtomicBool::new(false),
+            watcher,
+            keep_epochs,
+            authoritative,
+            pins: Arc::new(Mutex::new(BTreeMap::new())),
+            writing: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn info(&self) -> RootInfo {
+        RootInfo {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            authoritative: self.authoritative,
+        }
+    }
+
+    pub async fn stats(&self) -> Result<RootStats> {
+        let (epoch, maintenance) = {
+            let filesystem = self.filesystem.read().unwrap();
+            (filesystem.epoch(), filesystem.maintenance())
+        };
+        let storage = match maintenance {
+            Some(maintenance) => {
+                Some(tokio::task::spawn_blocking(move || maintenance.stats()).await??)
+            }
+            None => None,
+        };
+        Ok(RootStats {
+            name: self.name.clone(),
+            epoch,
+            pinned: self.pins.lock().unwrap().keys().copied().collect(),
+            storage,
+        })
+    }
+
+    /// Paths changed from epoch old to epoch new (the current one if not given), a page at a time
+    pub async fn diff(
+        &self,
+        old: u64,
+        new: Option<u64>,
+        after: String,
+        limit: usize,
+    ) -> Result<DiffPage> {
+        let (epoch, maintenance) = {
+            let filesystem = self.filesystem.read().unwrap();
+            (filesystem.epoch(), filesystem.maintenance())
+        };
+        let maintenance = maintenance
+            .ok_or_else(|| anyhow!("Epochs of {} are not kept by its backend", self.name))?;
+        let new = new.unwrap_or(epoch);
+        tokio::task::spawn_blocking(move || maintenance.diff(old, new, &after, limit)).await?
+    }
+
+    /// Pin the current epoch
+    pub async fn pin(self: &Arc<Self>) -> Result<Pin> {
+        let root = self.clone();
+        tokio::task::spawn_blocking(move || {
+            // the pin is counted before the guard is dropped,
+            // so that a refresh could not switch away from the epoch and collect it in between
+            let current = root.filesystem.read().unwrap();
+            let filesystem = current.pin()?;
+            let epoch = filesystem.epoch();
+            *root.pins.lock().unwrap().entry(epoch).or_insert(0) += 1;
+            drop(current);
+            Ok(Pin {
+                root: root.clone(),
+                epoch,
+                filesystem: Arc::new(filesystem),
+            })
+        })
+        .await?
+    }
+
+    /// Delete epochs beyond retention in the background
+    pub fn spawn_collect_garbage(&self) {
+        let filesystem = self.filesystem.clone();
+        let pins = self.pins.clone();
+        let writing = self.writing.clone();
+        let name = self.name.clone();
+        let keep = self.keep_epochs;
+        tokio::task::spawn_blocking(move || {
+            // nothing is guarded but the order, so a panic elsewhere does not matter
+            let _writing = writing.lock().unwrap_or_else(PoisonError::into_inner);
+            let (epoch, maintenance) = {
+                let filesystem = filesystem.read().unwrap();
+                (filesystem.epoch(), filesystem.maintenance())
+            };
+            let maintenance = match maintenance {
+                Some(maintenance) => maintenance,
+                None => return,
+            };
+            // pins are only taken on the current epoch, which stays current until this is done
+            let pinned: Vec<u64> = pins.lock().unwrap().keys().copied().collect();
+            match maintenance.collect_garbage(epoch, keep, &pinned) {
+                Ok(epochs) if !epochs.is_empty() => {
+                    info!("Deleted epochs {:?} of {}", epochs, name)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Garbage collection of {} failed: {}", name, e),
+            }
+        });
+    }
+
+    /// Look up path, following a symlink at the end if follow is set
+    pub async fn open(&self, path: PathBuf, follow: bool) -> Result<Inode> {
+        let filesystem = self.filesystem.clone();
+        // lookups may hit the disk, so keep them off the runtime
+        tokio::task::spawn_blocking(move || filesystem.read().unwrap().stat(&path, follow)).await?
+    }
+
+    /// Look up an inode by id, failing as stale once it is gone or of another generation
+    pub async fn get_by_inode(&self, id: InodeId, generation: Option<(i64, i64)>) -> Result<Inode> {
+        let filesystem = self.filesystem.clone();
+        tokio::task::spawn_blocking(move || filesystem.read().unwrap().get_by_inode(id, generation))
+            .await?
+    }
+
+    /// Every path of an inode relative to the root, failing as stale like get_by_inode
+    pub async fn paths(&self, id: InodeId, generation: Option<(i64, i64)>) -> Result<Vec<String>> {
+        let filesystem = self.filesystem.clone();
+        tokio::task::spawn_blocking(move || filesystem.read().unwrap().paths(id, generation))
+            .await?
+    }
+
+    /// A page of the entries of the directory at path
+    pub async fn readdir(
+        &self,
+        path: PathBuf,
+        after: String,
+        limit: usize,
+    ) -> Result<DirectoryPage> {
+        let filesystem = self.filesystem.clone();
+        tokio::task::spawn_blocking(move || {
+            filesystem.read().unwrap().readdir(&path, &after, limit)
+        })
+        .await?
+    }
+
+    /// Look up every path in the same epoch, each one failing on its own
+    pub async fn open_many(
+        &self,
+        paths: Vec<PathBuf>,
+        follow: bool,
+    ) -> Result<Vec<Result<Inode, BuhaoError>>> {
+        let filesystem = self.filesystem.clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            resolve_many(&filesystem.read().unwrap(), &paths, follow)
+        })
+        .await?)
+    }
+
+    /// Rescan the whole tree every interval
+    pub fn spawn_periodic_refresh(self: &Arc<Self>, interval: Duration) {
+        let root = self.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(interval);
+            // the first tick completes immediately, while the tree has just been scanned
+            timer.tick().await;
+            loop {
+                timer.tick().await;
+                if let Err(e) = root.refresh(None).await {
+                    warn!("Periodic refresh of {} failed: {}", root.name, e);
+                }
+            }
+        });
+    }
+
+    /// Refresh the subtree at path, or the whole tree if no path (or the root) is given
+    pub async fn refresh(&self, path: Option<PathBuf>) -> Result<u64> {
+        if self.refreshing.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!("Refresh of {} already in progress", self.name));
+        }
+        let result = self.refresh_locked(path).await;
+        self.refreshing.store(false, Ordering::SeqCst);
+        result
+    }
+
+    async fn refresh_locked(&self, path: Option<PathBuf>) -> Result<u64> {
+        let (epoch, path) = match path {
+            Some(path) if self.path.join(&path) != self.path => {
+                (self.refresh_subtree(path.clone()).await?, path)
+            }
+            _ => (self.refresh_full().await?, PathBuf::new()),
+        };
+        // directories that showed up in the rescan need watches too
+        if let Some(ref watcher) = self.watcher {
+            let watcher = watcher.clone();
+            let filesystem = self.filesystem.clone();
+            tokio::task::spawn_blocking(move || watcher.watch(&filesystem, &path)).await?;
+        }
+        Ok(epoch)
+    }
+
+    /// Rescan only the subtree at path, and splice it into the current epoch.
+    /// Readers are only held up by the splice, not by the scan.
+    async fn refresh_subtree(&self, path: PathBuf) -> Result<u64> {
+        let root_path = self.path.clone();
+        let filesystem = self.filesystem.clone();
+        tokio::task::spawn_blocking(move || {
+            let scanner = filesystem.read().unwrap().scanner();
+            let scan = scan_subtree(&root_path, &scanner, &path)?;
+            let mut filesystem = filesystem.write().unwrap();
+            filesystem.splice(scan)?;
+            Ok(filesystem.epoch())
+        })
+        .await?
+    }
+
+    /// Rescan the whole tree into a new epoch, and switch to it when the scan is done.
+    /// Gets are served from the old epoch in the meantime.
+    async fn refresh_full(&self) -> Result<u64> {
+        let filesystem = self.filesystem.clone();
+        let writing = self.writing.clone();
+        let epoch = tokio::task::spawn_blocking(move || -> Result<u64> {
+            let _writing = writing.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut next = filesystem.read().unwrap().next_epoch()?;
+            next.populate()?;
+            let epoch = next.epoch();
+            *filesystem.write().unwrap() = next;
+            Ok(epoch)
+        })
+        .await??;
+        info!("Switched {} to epoch {}", self.name, epoch);
+        self.spawn_collect_garbage();
+        Ok(epoch)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Roots {
+    roots: Vec<Arc<Root>>,
+}
+
+impl Roots {
+    pub fn add(&mut self, root: Root) -> Result<()> {
+        if self.get(&root.name).is_some() {
+            return Err(anyhow!("Duplicated root name: {}", root.name));
+        }
+        self.roots.push(Arc::new(root));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Root>> {
+        self.roots.iter().find(|root| root.name == name).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Root>> {
+        self.roots.iter()
+    }
+
+    /// Find the root managing path, together with the path to look up in it.
+    /// Absolute paths go to the root with the longest matching prefix,
+    /// and relative ones to the root named by their first component.
+    pub fn route(&self, path: &Path) -> Option<(Arc<Root>, PathBuf)> {
+        if path.is_absolute() {
+            self.roots
+                .iter()
+                .filter(|root| path.starts_with(&root.path))
+                .max_by_key(|root| root.path.components().count())
+                .map(|root| (root.clone(), path.to_path_buf()))
+        } else {
+            let mut components = path.components();
+            let name = loop {
+                match components.next()? {
+                    Component::CurDir => continue,
+                    Component::Normal(name) => break name.to_str()?,
+                    _ => return None,
+                }
+            };
+            let root = self.get(name)?;
+            Some((root, components.as_path().to_path_buf()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filter::Filter;
+    use crate::scanner::Scanner;
+    use buhao_lib::{Contents, ErrorKind};
+    use std::os::unix::fs::MetadataExt;
+    use test_log::test;
+
+    #[test]
+    fn test_route() {
+        let base = Path::new("/tmp/buhao-roots");
+        std::fs::create_dir_all(base.join("a")).unwrap();
+        std::fs::create_dir_all(base.join("a/nested")).unwrap();
+        let mut roots = Roots::default();
+        for (name, path) in [("a", "a"), ("nested", "a/nested")] {
+            let filesystem = Filesystem::new_from_fs(
+                &base.join(path),
+                Scanner::new(Filter::default(), 0).unwrap(),
+            )
+            .unwrap();
+            roots
+                .add(Root::new(name, filesystem, None, 2, false).unwrap())
+                .unwrap();
+        }
+        let filesystem =
+            Filesystem::new_from_fs(&base.join("a"), Scanner::new(Filter::default(), 0).unwrap())
+                .unwrap();
+        assert!(roots
+            .add(Root::new("a", filesystem, None, 2, false).unwrap())
+            .is_err());
+
+        let (root, path) = roots.route(Path::new("/tmp/buhao-roots/a/x")).unwrap();
+        assert_eq!(root.name, "a");
+        assert_eq!(path, Path::new("/tmp/buhao-roots/a/x"));
+        // the longest prefix wins
+        let (root, _) = roots
+            .route(Path::new("/tmp/buhao-roots/a/nested/x"))
+            .unwrap();
+        assert_eq!(root.name, "nested");
+        // component-wise, not string prefix
+        assert!(roots.route(Path::new("/tmp/buhao-roots/ab")).is_none());
+
+        let (root, path) = roots.route(Path::new("./nested/x/y")).unwrap();
+        assert_eq!(root.name, "nested");
+        assert_eq!(path, Path::new("x/y"));
+        assert!(roots.route(Path::new("missing/x")).is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn test_pin() {
+        let base = Path::new("/tmp/buhao-pin");
+        let db = Path::new("/tmp/buhao-pin.db");
+        std::fs::remove_dir_all(base).unwrap_or(());
+        std::fs::remove_file(db).unwrap_or(());
+        std::fs::create_dir_all(base.join("old")).unwrap();
+        let metadata = std::fs::metadata(base.join("old")).unwrap();
+        let old = metadata.ino();
+        let generation = Inode::new(metadata, Contents::File).generation().unwrap();
+        let filesystem = Filesystem::new_from_sqlite(
+            "pin",
+            base,
+            db,
+            Scanner::new(Filter::default(), 0).unwrap(),
+        )
+        .unwrap();
+        let root = Arc::new(Root::new("pin", filesystem, None, 1, false).unwrap());
+
+        let pin = root.pin().await.unwrap();
+        std::fs::remove_dir(base.join("old")).unwrap();
+        std::fs::create_dir(base.join("new")).unwrap();
+        let epoch = root.refresh(None).await.unwrap();
+        assert!(epoch > pin.epoch());
+        // the next refresh collects everything but the current epoch, and the pinned one
+        let current = root.refresh(None).await.unwrap();
+        let mut epochs = Vec::new();
+        for _ in 0..50 {
+            let stats = root.stats().await.unwrap();
+            assert_eq!(stats.pinned, vec![pin.epoch()]);
+            epochs = stats.storage.unwrap().epochs;
+            if epochs.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let epochs: Vec<_> = epochs.iter().map(|stats| stats.epoch).collect();
+        assert_eq!(epochs, vec![pin.epoch(), current]);
+        assert!(pin.open(PathBuf::from("old"), false).await.is_ok());
+        assert!(pin.open(PathBuf::from("new"), false).await.is_err());
+        assert!(root.open(PathBuf::from("new"), false).await.is_ok());
+        // stale even if new took the inode number of old
+        assert!(pin.get_by_inode(old, Some(generation)).await.is_ok());
+        let stale = root.get_by_inode(old, Some(generation)).await.unwrap_err();
+        let stale = BuhaoError::from_anyhow(&stale);
+        assert_eq!(stale.kind, ErrorKind::Stale);
+
+        drop(pin);
+        assert!(root.stats().await.unwrap().pinned.is_empty());
+    }
+}
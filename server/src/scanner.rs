@@ -0,0 +1,223 @@
+use std::{
+    os::unix::prelude::MetadataExt,
+    path::Path,
+    sync::mpsc::{sync_channel, SyncSender},
+};
+
+use anyhow::{anyhow, Result};
+use buhao_lib::{Contents, DirectoryContents, DirectoryItem, Inode, InodeId};
+use log::warn;
+use rayon::prelude::*;
+
+use crate::filter::Filter;
+use crate::hashmapshim::{InodeMap, Transaction};
+
+/// Inodes handed to the writer at once
+const BATCH_SIZE: usize = 4096;
+
+/// Batches queued for the writer before scanning threads wait for it
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Parallel tree walker.
+///
+/// Directories are split into tasks on a work-stealing pool, so a slow `stat` on NFS only holds up one thread.
+/// Scanned inodes are sent in batches to the calling thread, which is the only one writing to the map.
+/// Every directory is sent after its children, same as a sequential depth-first walk.
+#[derive(Debug)]
+pub struct Scanner {
+    filter: Filter,
+    pool: rayon::ThreadPool,
+}
+
+impl Scanner {
+    /// Scan with `threads` threads, or one per CPU if it is 0
+    pub fn new(filter: Filter, threads: usize) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("buhao-scanner-{}", i))
+            .build()?;
+        Ok(Self { filter, pool })
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Scan the entry at path, whose path relative to the root is `relative`, and everything below it into inodes.
+    /// Returns None for entries that are not cached, like sockets and devices.
+    pub fn scan(
+        &self,
+        inodes: &mut dyn InodeMap,
+        path: &Path,
+        relative: &Path,
+        metadata: &std::fs::Metadata,
+        parent: InodeId,
+    ) -> Result<Option<Contents>> {
+        let (sender, receiver) = sync_channel::<Vec<Inode>>(CHANNEL_CAPACITY);
+        std::thread::scope(|scope| {
+            let scan = scope.spawn(move || {
+                self.pool
+                    .install(|| self.contents(&sender, path, relative, metadata, parent))
+            });
+            // the channel is closed when the scan is done, and sender is dropped
+            let mut written = Ok(());
+            let mut batch = Vec::new();
+            for inodes_ in receiver {
+                // after a failed write, the rest of the scan is drained and thrown away
+                if written.is_err() {
+                    continue;
+                }
+                batch.extend(inodes_);
+                if batch.len() >= BATCH_SIZE {
+                    written = write(inodes, &mut batch);
+                }
+            }
+            if written.is_ok() {
+                written = write(inodes, &mut batch);
+            }
+            let contents = scan.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+            written.and(contents)
+        })
+    }
+
+    fn contents(
+        &self,
+        sender: &SyncSender<Vec<Inode>>,
+        path: &Path,
+        relative: &Path,
+        metadata: &std::fs::Metadata,
+        parent: InodeId,
+    ) -> Result<Option<Contents>> {
+        let filetype = metadata.file_type();
+        let contents = if filetype.is_symlink() {
+            let target = std::fs::read_link(path)?;
+            Contents::Symlink(target.to_string_lossy().to_string())
+        } else if filetype.is_file() {
+            Contents::File
+        } else if filetype.is_dir() {
+            let children = self.list(sender, path, relative, metadata.ino())?;
+            Contents::Directory(DirectoryContents { parent, children })
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(contents))
+    }
+
+    /// Scan the entries of directory dir in parallel, and send their inodes to the writer
+    fn list(
+        &self,
+        sender: &SyncSender<Vec<Inode>>,
+        dir: &Path,
+        relative: &Path,
+        self_id: InodeId,
+    ) -> Result<Vec<DirectoryItem>> {
+        let entries: Vec<_> = std::fs::read_dir(dir)?
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!("Failed to read directory {:?} entry: {}", dir, e);
+                    None
+                }
+            })
+            .collect();
+        let (items, inodes): (Vec<_>, Vec<_>) = entries
+            .par_iter()
+            .filter_map(|entry| self.entry(sender, entry, relative, self_id))
+            .unzip();
+        sender
+            .send(inodes)
+            .map_err(|_| anyhow!("Scan of {:?} abandoned", dir))?;
+        Ok(items)
+    }
+
+    fn entry(
+        &self,
+        sender: &SyncSender<Vec<Inode>>,
+        entry: &std::fs::DirEntry,
+        relative: &Path,
+        self_id: InodeId,
+    ) -> Option<(DirectoryItem, Inode)> {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(ref e) => {
+                warn!("Failed to read {:?} metadata: {}", entry, e);
+                return None;
+            }
+        };
+        let relative = relative.join(entry.file_name());
+        if self.filter.is_excluded(&relative, metadata.is_dir()) {
+            return None;
+        }
+        let contents = match self.contents(sender, &entry.path(), &relative, &metadata, self_id) {
+            Ok(Some(contents)) => contents,
+            Ok(None) => return None,
+            Err(ref e) => {
+                warn!("Failed to read {:?} contents: {}", entry, e);
+                return None;
+            }
+        };
+        let inode = Inode::new(metadata, contents);
+        let item = DirectoryItem {
+            name: entry.file_name().to_string_lossy().to_string(),
+            inode: inode.id,
+            itype: inode.itype(),
+        };
+        Some((item, inode))
+    }
+}
+
+/// Write a batch in one transaction
+fn write(inodes: &mut dyn InodeMap, batch: &mut Vec<Inode>) -> Result<()> {
+    let mut inodes = Transaction::begin(inodes)?;
+    inodes.insert_many(batch.drain(..).map(|inode| (inode.id, inode)).collect())?;
+    inodes.commit()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use super::*;
+    use crate::hashmapshim::{HashMapShim, StdHashMap};
+    use buhao_lib::INVALID_PARENT;
+    use test_log::test;
+
+    #[test]
+    fn test_parallel_scan() {
+        let root = Path::new("/tmp/buhao-scanner");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        for i in 0..20 {
+            for j in 0..20 {
+                let dir = root.join(format!("d{}/e{}", i, j));
+                std::fs::create_dir_all(&dir).unwrap();
+                File::create(dir.join("f")).unwrap();
+            }
+        }
+        let metadata = std::fs::metadata(root).unwrap();
+
+        let mut sequential = StdHashMap::new();
+        let mut parallel = StdHashMap::new();
+        let scan = |inodes: &mut StdHashMap, threads| {
+            Scanner::new(Filter::default(), threads)
+                .unwrap()
+                .scan(inodes, root, Path::new(""), &metadata, INVALID_PARENT)
+                .unwrap()
+                .unwrap()
+        };
+        let expected = scan(&mut sequential, 1);
+        let contents = scan(&mut parallel, 8);
+        assert_eq!(
+            serde_json::to_value(&contents).unwrap(),
+            serde_json::to_value(&expected).unwrap()
+        );
+        // 20 + 400 directories and 400 files, without the root
+        assert_eq!(parallel.values().len(), 820);
+        // the same entries in the same order, atime aside
+        for inode in sequential.values() {
+            assert_eq!(
+                serde_json::to_value(parallel.get(&inode.id).unwrap().unwrap().contents).unwrap(),
+                serde_json::to_value(inode.contents).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::Result;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use log::{debug, error, warn};
+
+use crate::fs::{scan_subtree, Filesystem};
+
+/// Changes of entries inside a watched directory, and attribute changes of the directory itself
+const WATCH_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::ATTRIB)
+    .union(WatchMask::MODIFY)
+    .union(WatchMask::ONLYDIR)
+    .union(WatchMask::DONT_FOLLOW)
+    .union(WatchMask::EXCL_UNLINK);
+
+/// Keeps the cache current between refreshes, by rescanning whatever inotify reports as changed
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    watches: Watches,
+    /// Watched directories, relative to root_path
+    paths: Arc<Mutex<HashMap<WatchDescriptor, PathBuf>>>,
+    root_path: PathBuf,
+    max_watches: usize,
+}
+
+impl Watcher {
+    /// Watch every cached directory, and apply events in a background thread
+    pub fn spawn(filesystem: Arc<RwLock<Filesystem>>, max_watches: usize) -> Result<Self> {
+        let inotify = Inotify::init()?;
+        let root_path = filesystem.read().unwrap().root_path().to_path_buf();
+        let watcher = Self {
+            watches: inotify.watches(),
+            paths: Arc::new(Mutex::new(HashMap::new())),
+            root_path,
+            max_watches,
+        };
+        watcher.watch(&filesystem, Path::new(""));
+        let runner = watcher.clone();
+        std::thread::Builder::new()
+            .name("buhao-watcher".to_string())
+            .spawn(move || runner.run(inotify, filesystem))?;
+        Ok(watcher)
+    }
+
+    /// Add watches for path and every directory below it.
+    /// Watching a directory again only updates the path it is known by, even at the limit of watches.
+    pub fn watch(&self, filesystem: &RwLock<Filesystem>, path: &Path) {
+        let directories = match filesystem.read().unwrap().directories(path) {
+            Ok(directories) => directories,
+            Err(e) => {
+                warn!("Failed to list directories under {:?}: {}", path, e);
+                return;
+            }
+        };
+        self.add_watches(directories);
+    }
+
+    /// Add watches for directories, relative to root_path
+    fn add_watches(&self, directories: Vec<PathBuf>) {
+        let mut watches = self.watches.clone();
+        let mut paths = self.paths.lock().unwrap();
+        for directory in directories {
+            let wd = match watches.add(self.root_path.join(&directory), WATCH_MASK) {
+                Ok(wd) => wd,
+                Err(e) => {
+                    warn!("Failed to watch {:?}: {}", directory, e);
+                    continue;
+                }
+            };
+            // only new watches count against the limit, as inotify hands out the same one again
+            if !paths.contains_key(&wd) && paths.len() >= self.max_watches {
+                warn!(
+                    "Watch limit {} reached, {:?} is not watched",
+                    self.max_watches, directory
+                );
+                watches.remove(wd).ok();
+                continue;
+            }
+            paths.insert(wd, directory);
+        }
+    }
+
+    /// Watched directories at or below from follow it to to, or are unwatched if it left the tree
+    fn moved(&self, paths: &mut HashMap<WatchDescriptor, PathBuf>, from: &Path, to: Option<&Path>) {
+        let mut watches = self.watches.clone();
+        paths.retain(|wd, path| {
+            let Ok(rest) = path.strip_prefix(from) else {
+                return true;
+            };
+            match to {
+                Some(to) => {
+                    *path = to.join(rest);
+                    true
+                }
+                None => {
+                    watches.remove(wd.clone()).ok();
+                    false
+                }
+            }
+        });
+    }
+
+    fn run(self, mut inotify: Inotify, filesystem: Arc<RwLock<Filesystem>>) {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Failed to read inotify events, stop watching: {}", e);
+                    return;
+                }
+            };
+            let mut changed = BTreeSet::new();
+            let mut overflow = false;
+            {
+                let mut paths = self.paths.lock().unwrap();
+                // directories moved away from a watched directory by cookie, until they show up again
+                let mut moves = HashMap::new();
+                for event in events {
+                    if event.mask.contains(EventMask::Q_OVERFLOW) {
+                        overflow = true;
+                        continue;
+                    }
+                    if event.mask.contains(EventMask::IGNORED) {
+                        paths.remove(&event.wd);
+                        continue;
+                    }
+                    let directory = match paths.get(&event.wd) {
+                        Some(directory) => directory,
+                        None => continue,
+                    };
+                    debug!("inotify: {:?} {:?} {:?}", directory, event.name, event.mask);
+                    let path = match event.name {
+                        Some(name) => directory.join(name),
+                        None => directory.clone(),
+                    };
+                    if event.mask.contains(EventMask::ISDIR) {
+                        if event.mask.contains(EventMask::MOVED_FROM) {
+                            moves.insert(event.cookie, path.clone());
+                        } else if event.mask.contains(EventMask::MOVED_TO) {
+                            if let Some(from) = moves.remove(&event.cookie) {
+                                self.moved(&mut paths, &from, Some(&path));
+                            }
+                        }
+                    }
+                    changed.insert(path);
+                }
+                // the other half of a move split across reads is watched again by its refresh
+                for from in moves.into_values() {
+                    self.moved(&mut paths, &from, None);
+                }
+            }
+            if overflow {
+                warn!("inotify queue overflowed, rescanning the whole tree");
+                changed = self.root_entries(&filesystem);
+            }
+
+            // sorted, so that a refreshed directory comes right before everything below it
+            let mut last: Option<PathBuf> = None;
+            for path in changed {
+                if path.as_os_str().is_empty() {
+                    // the root itself could only be rescanned as a whole
+                    continue;
+                }
+                if let Some(ref last) = last {
+                    if path.starts_with(last) {
+                        continue;
+                    }
+                }
+                self.refresh(&filesystem, &path);
+                last = Some(path);
+            }
+        }
+    }
+
+    /// Names in the root, both on disk and in the cache
+    fn root_entries(&self, filesystem: &RwLock<Filesystem>) -> BTreeSet<PathBuf> {
+        let mut entries = BTreeSet::new();
+        match std::fs::read_dir(&self.root_path) {
+            Ok(dir) => {
+                for entry in dir.flatten() {
+                    entries.insert(PathBuf::from(entry.file_name()));
+                }
+            }
+            Err(e) => warn!("Failed to read {:?}: {}", self.root_path, e),
+        }
+        if let Ok(directories) = filesystem.read().unwrap().children(Path::new("")) {
+            entries.extend(directories.into_iter().map(PathBuf::from));
+        }
+        entries
+    }
+
+    /// Watches are added before a directory is scanned, and the ones below it before readers see them,
+    /// so that entries created in the meantime still get their events.
+    fn refresh(&self, filesystem: &RwLock<Filesystem>, path: &Path) {
+        let scanner = filesystem.read().unwrap().scanner();
+        let is_directory = std::fs::symlink_metadata(self.root_path.join(path))
+            .is_ok_and(|metadata| metadata.is_dir());
+        if is_directory && !scanner.filter().is_excluded(path, true) {
+            self.add_watches(vec![path.to_path_buf()]);
+        }
+        let scan = match scan_subtree(&self.root_path, &scanner, path) {
+            Ok(scan) => scan,
+            Err(e) => {
+                warn!("Failed to rescan {:?}: {}", path, e);
+                return;
+            }
+        };
+        match scan.directories() {
+            Ok(directories) => self.add_watches(directories),
+            Err(e) => warn!("Failed to list directories under {:?}: {}", path, e),
+        }
+        if let Err(e) = filesystem.write().unwrap().splice(scan) {
+            warn!("Failed to update {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, time::Duration};
+
+    use super::*;
+    use crate::filter::Filter;
+    use crate::scanner::Scanner;
+    use test_log::test;
+
+    fn wait_for(filesystem: &RwLock<Filesystem>, path: &str, exists: bool) -> bool {
+        for _ in 0..50 {
+            if filesystem.read().unwrap().open(Path::new(path)).is_ok() == exists {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    #[test]
+    fn test_watcher() {
+        let root = Path::new("/tmp/buhao-watch");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::create_dir_all(root.join("a")).unwrap();
+
+        let filesystem = Arc::new(RwLock::new(
+            Filesystem::new_from_fs(root, Scanner::new(Filter::default(), 0).unwrap()).unwrap(),
+        ));
+        let _watcher = Watcher::spawn(filesystem.clone(), 100).unwrap();
+
+        File::create(root.join("a/file")).unwrap();
+        assert!(wait_for(&filesystem, "./a/file", true));
+
+        // a new directory gets watched as well
+        std::fs::create_dir_all(root.join("b/c")).unwrap();
+        assert!(wait_for(&filesystem, "./b/c", true));
+        File::create(root.join("b/c/file")).unwrap();
+        assert!(wait_for(&filesystem, "./b/c/file", true));
+
+        std::fs::rename(root.join("b"), root.join("d")).unwrap();
+        assert!(wait_for(&filesystem, "./d/c/file", true));
+        assert!(wait_for(&filesystem, "./b", false));
+
+        std::fs::remove_file(root.join("a/file")).unwrap();
+        assert!(wait_for(&filesystem, "./a/file", false));
+    }
+
+    /// A directory is watched by the time readers see it, so that nothing created in it is missed
+    #[test]
+    fn test_watch_before_shown() {
+        let root = Path::new("/tmp/buhao-watch-order");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::create_dir_all(root).unwrap();
+
+        let filesystem = Arc::new(RwLock::new(
+            Filesystem::new_from_fs(root, Scanner::new(Filter::default(), 0).unwrap()).unwrap(),
+        ));
+        let watcher = Watcher::spawn(filesystem.clone(), 1000).unwrap();
+        let watched = |path: &str| {
+            let paths = watcher.paths.lock().unwrap();
+            paths.values().any(|watched| watched == Path::new(path))
+        };
+        for i in 0..20 {
+            let path = format!("d{}/e", i);
+            std::fs::create_dir_all(root.join(&path)).unwrap();
+            // polled without a pause, to catch it as soon as it shows up
+            let start = std::time::Instant::now();
+            while filesystem.read().unwrap().open(Path::new(&path)).is_err() {
+                assert!(start.elapsed() < Duration::from_secs(5));
+            }
+            assert!(watched(&path));
+            File::create(root.join(&path).join("file")).unwrap();
+            assert!(wait_for(&filesystem, &format!("{}/file", path), true));
+        }
+    }
+
+    #[test]
+    fn test_watch_limit() {
+        let root = Path::new("/tmp/buhao-watch-limit");
+        let outside = Path::new("/tmp/buhao-watch-limit-outside");
+        std::fs::remove_dir_all(root).unwrap_or(());
+        std::fs::remove_dir_all(outside).unwrap_or(());
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::create_dir_all(root.join("b/c")).unwrap();
+
+        let filesystem = Arc::new(RwLock::new(
+            Filesystem::new_from_fs(root, Scanner::new(Filter::default(), 0).unwrap()).unwrap(),
+        ));
+        // the root, a, b and b/c
+        let watcher = Watcher::spawn(filesystem.clone(), 4).unwrap();
+        let watched = || {
+            let mut paths: Vec<_> = watcher.paths.lock().unwrap().values().cloned().collect();
+            paths.sort();
+            paths
+        };
+        let wait_for_watched = |expected: &[&str]| {
+            let expected: Vec<PathBuf> = expected.iter().map(PathBuf::from).collect();
+            for _ in 0..50 {
+                if watched() == expected {
+                    return true;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            false
+        };
+        assert!(wait_for_watched(&["", "a", "b", "b/c"]));
+
+        // past the limit, but renamed directories keep their watches under the new path
+        std::fs::create_dir(root.join("d")).unwrap();
+        assert!(wait_for(&filesystem, "./d", true));
+        std::fs::rename(root.join("b"), root.join("e")).unwrap();
+        assert!(wait_for(&filesystem, "./e/c", true));
+        assert!(wait_for_watched(&["", "a", "e", "e/c"]));
+        watcher.watch(&filesystem, Path::new(""));
+        assert_eq!(watched().len(), 4);
+
+        // and moving out of the tree drops them
+        std::fs::rename(root.join("e"), outside).unwrap();
+        assert!(wait_for_watched(&["", "a"]));
+        File::create(outside.join("c/file")).unwrap();
+        assert!(wait_for(&filesystem, "./e", false));
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+}